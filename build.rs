@@ -13,11 +13,13 @@ fn main() {
         eprintln!("{:?}", e);
         std::process::exit(1);
     }
-    // track line numbers
+    // track line numbers. We count the newlines of the previous match right
+    // before starting the next one: counting while scanning would also count
+    // newlines the scanner only looked at (and later gave back).
     let d = std::fs::read_to_string(&dest).unwrap();
     let d = d.replace(
-        "let idx = Lexer::ZZ_ROW",
-        "if zz_input == 0x0a { self.zz_lineno += 1; }\nlet idx = Lexer::ZZ_ROW",
+        "self.zz_start_read = self.zz_marked_pos;",
+        "self.zz_lineno += self.start.clone().take(self.zz_marked_pos - self.zz_start_read).filter(|&c| c == '\\n').count();\nself.zz_start_read = self.zz_marked_pos;",
     );
     let d = d.replace(
         "zz_state: usize,",
@@ -0,0 +1,1246 @@
+//! The scope every evaluation starts in: the `builtins` set plus the names
+//! nix also makes available globally (`true`, `map`, `import`, ...), and the
+//! primops behind them.
+use crate::eval::{
+    arithmetic, bool_value, canonical_path, close, compare_values, peek, suggestions, ErrorKind, Need,
+};
+use crate::expr::{AttrSet, Comparison, Cont, Env, Expr, GcEnv, GcExpr, GcStack, Merged};
+use crate::host::Host;
+use gc_arena::{Gc, MutationContext};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+/// What a primop does once its strict arguments are values. It's handed the
+/// name it was called by, so related primops can share one function. Like a
+/// step it can also push a continuation and return something to evaluate
+/// first.
+pub type PrimOpFn = for<'gc> fn(
+    MutationContext<'gc, '_>,
+    &'static str,
+    &[GcExpr<'gc>],
+    GcEnv<'gc>,
+    GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind>;
+
+pub struct PrimOp {
+    pub name: &'static str,
+    pub arity: usize,
+    /// Bit `i` is set if argument `i` is evaluated before calling `f`. The
+    /// others are passed on as they are, i.e. usually as thunks.
+    pub strict: u32,
+    pub f: PrimOpFn,
+}
+
+impl PrimOp {
+    /// A primop that gets all of its arguments evaluated.
+    const fn strict(name: &'static str, arity: usize, f: PrimOpFn) -> PrimOp {
+        PrimOp {
+            name,
+            arity,
+            strict: !0,
+            f,
+        }
+    }
+
+    /// A primop that only gets the arguments in `strict` evaluated.
+    const fn lazy(name: &'static str, arity: usize, strict: u32, f: PrimOpFn) -> PrimOp {
+        PrimOp { name, arity, strict, f }
+    }
+
+    pub fn is_strict(&self, arg: usize) -> bool {
+        self.strict & (1 << arg) != 0
+    }
+}
+
+/// Everything `Expr::PrimOp` can refer to, including the operators.
+static PRIMOPS: &[PrimOp] = &[
+    PrimOp::strict("+", 2, add),
+    PrimOp::strict("-", 2, arithmetic_op),
+    PrimOp::strict("*", 2, arithmetic_op),
+    PrimOp::strict("/", 2, arithmetic_op),
+    PrimOp::strict("<", 2, compare),
+    PrimOp::strict("<=", 2, compare),
+    PrimOp::strict(">", 2, compare),
+    PrimOp::strict(">=", 2, compare),
+    PrimOp::strict("==", 2, compare),
+    PrimOp::strict("!=", 2, compare),
+    PrimOp::strict("++", 2, concat_lists),
+    PrimOp::strict("//", 2, update),
+    PrimOp::strict("abort", 1, throw),
+    PrimOp::lazy("all", 2, 0b10, any_all),
+    PrimOp::lazy("any", 2, 0b10, any_all),
+    PrimOp::strict("attrNames", 1, attr_names),
+    PrimOp::strict("attrValues", 1, attr_values),
+    PrimOp::strict("baseNameOf", 1, not_implemented),
+    PrimOp::strict("bitAnd", 2, bitwise),
+    PrimOp::strict("bitOr", 2, bitwise),
+    PrimOp::strict("bitXor", 2, bitwise),
+    PrimOp::strict("catAttrs", 2, cat_attrs),
+    PrimOp::strict("concatLists", 1, concat_all),
+    PrimOp::lazy("concatMap", 2, 0b10, concat_map),
+    PrimOp::strict("derivation", 1, not_implemented),
+    PrimOp::strict("dirOf", 1, not_implemented),
+    PrimOp::strict("elem", 2, compare),
+    PrimOp::strict("elemAt", 2, elem_at),
+    PrimOp::lazy("filter", 2, 0b10, filter),
+    // neither the function nor the initial value, but every intermediate result
+    PrimOp::lazy("foldl'", 3, 0b100, foldl),
+    PrimOp::strict("functionArgs", 1, function_args),
+    PrimOp::lazy("genList", 2, 0b10, gen_list),
+    PrimOp::strict("getAttr", 2, get_attr),
+    PrimOp::lazy("groupBy", 2, 0b10, group_by),
+    PrimOp::strict("hasAttr", 2, has_attr),
+    PrimOp::strict("head", 1, head),
+    PrimOp::strict("import", 1, not_implemented),
+    PrimOp::strict("intersectAttrs", 2, intersect_attrs),
+    PrimOp::strict("isAttrs", 1, is_type),
+    PrimOp::strict("isFunction", 1, is_type),
+    PrimOp::strict("isList", 1, is_type),
+    PrimOp::strict("isNull", 1, is_type),
+    PrimOp::strict("length", 1, length),
+    PrimOp::strict("listToAttrs", 1, list_to_attrs),
+    // only the list, the function is applied lazily to each element
+    PrimOp::lazy("map", 2, 0b10, map),
+    // the function is applied lazily to each value
+    PrimOp::lazy("mapAttrs", 2, 0b10, map_attrs),
+    PrimOp::lazy("partition", 2, 0b10, filter),
+    PrimOp::strict("removeAttrs", 2, remove_attrs),
+    PrimOp::lazy("sort", 2, 0b10, sort),
+    PrimOp::strict("tail", 1, tail),
+    PrimOp::strict("throw", 1, throw),
+    PrimOp::strict("toString", 1, to_string),
+    PrimOp::lazy("zipAttrsWith", 2, 0b10, zip_attrs_with),
+];
+
+/// (name in `builtins`, primop name, also global?)
+const BUILTINS: &[(&str, &str, bool)] = &[
+    ("abort", "abort", true),
+    ("add", "+", false),
+    ("all", "all", false),
+    ("any", "any", false),
+    ("attrNames", "attrNames", false),
+    ("attrValues", "attrValues", false),
+    ("baseNameOf", "baseNameOf", true),
+    ("bitAnd", "bitAnd", false),
+    ("bitOr", "bitOr", false),
+    ("bitXor", "bitXor", false),
+    ("catAttrs", "catAttrs", false),
+    ("concatLists", "concatLists", false),
+    ("concatMap", "concatMap", false),
+    ("derivation", "derivation", true),
+    ("dirOf", "dirOf", true),
+    ("div", "/", false),
+    ("elem", "elem", false),
+    ("elemAt", "elemAt", false),
+    ("filter", "filter", false),
+    ("foldl'", "foldl'", false),
+    ("functionArgs", "functionArgs", false),
+    ("genList", "genList", false),
+    ("getAttr", "getAttr", false),
+    ("groupBy", "groupBy", false),
+    ("hasAttr", "hasAttr", false),
+    ("head", "head", false),
+    ("import", "import", true),
+    ("intersectAttrs", "intersectAttrs", false),
+    ("isAttrs", "isAttrs", false),
+    ("isFunction", "isFunction", false),
+    ("isList", "isList", false),
+    ("isNull", "isNull", true),
+    ("length", "length", false),
+    ("lessThan", "<", false),
+    ("listToAttrs", "listToAttrs", false),
+    ("map", "map", true),
+    ("mapAttrs", "mapAttrs", false),
+    ("mul", "*", false),
+    ("partition", "partition", false),
+    ("removeAttrs", "removeAttrs", true),
+    ("sort", "sort", false),
+    ("sub", "-", false),
+    ("tail", "tail", false),
+    ("throw", "throw", true),
+    ("toString", "toString", true),
+    ("zipAttrsWith", "zipAttrsWith", false),
+];
+
+/// The primop called `name`, e.g. "+" or "map". This runs for every primop
+/// application, so it looks the name up in a map built on first use.
+pub fn primop(name: &str) -> Option<&'static PrimOp> {
+    static BY_NAME: OnceLock<HashMap<&str, &PrimOp>> = OnceLock::new();
+    let by_name = BY_NAME.get_or_init(|| PRIMOPS.iter().map(|op| (op.name, op)).collect());
+    by_name.get(name).copied()
+}
+
+pub fn root_env<'gc>(mc: MutationContext<'gc, '_>) -> GcEnv<'gc> {
+    root_env_with(mc, &Host::new())
+}
+
+/// `root_env` plus whatever the application adds. Its functions go into
+/// `builtins` too, its values don't.
+pub fn root_env_with<'gc>(mc: MutationContext<'gc, '_>, host: &Host) -> GcEnv<'gc> {
+    let mut globals: HashMap<String, GcExpr<'gc>> = HashMap::new();
+    globals.insert("true".to_string(), Gc::allocate(mc, Expr::Bool(true)));
+    globals.insert("false".to_string(), Gc::allocate(mc, Expr::Bool(false)));
+    globals.insert("null".to_string(), Gc::allocate(mc, Expr::Null()));
+
+    let mut builtins = AttrSet::new();
+    for (name, value) in globals.iter() {
+        builtins.insert(mc, name.clone(), *value);
+    }
+    for &(name, op, global) in BUILTINS {
+        let op = primop(op).expect("builtin without a primop");
+        let primop = Gc::allocate(
+            mc,
+            Expr::PrimOp {
+                name: op.name,
+                arity: op.arity,
+            },
+        );
+        builtins.insert(mc, name.to_string(), primop);
+        if global {
+            globals.insert(name.to_string(), primop);
+        }
+    }
+    for f in &host.functions {
+        let primop = Gc::allocate(mc, Expr::HostFn(f.clone()));
+        builtins.insert(mc, f.name.clone(), primop);
+        globals.insert(f.name.clone(), primop);
+    }
+    for (name, value) in &host.values {
+        globals.insert(name.clone(), value.to_expr(mc));
+    }
+    let builtins = Gc::allocate(mc, Expr::AttrSet(builtins));
+    globals.insert("builtins".to_string(), builtins);
+    Gc::allocate(mc, Env::new(None, globals))
+}
+
+fn type_error(expected: &'static str, got: &Expr) -> ErrorKind {
+    ErrorKind::TypeError {
+        expected,
+        got: got.type_name(),
+    }
+}
+
+fn string<'a>(expr: &'a Expr) -> Result<&'a str, ErrorKind> {
+    match expr {
+        Expr::String(s) => Ok(s),
+        e => Err(type_error("a string", e)),
+    }
+}
+
+fn list<'a, 'gc>(expr: &'a Expr<'gc>) -> Result<&'a [GcExpr<'gc>], ErrorKind> {
+    match expr {
+        Expr::List(elems) => Ok(elems),
+        e => Err(type_error("a list", e)),
+    }
+}
+
+fn set<'a, 'gc>(expr: &'a Expr<'gc>) -> Result<&'a AttrSet<'gc>, ErrorKind> {
+    match expr {
+        Expr::AttrSet(attrs) => Ok(attrs),
+        e => Err(type_error("a set", e)),
+    }
+}
+
+/// The values behind `elems`, once they've all been forced. `more` can ask
+/// for something else to be forced for an element before the next one, e.g.
+/// one of its attributes. Like `force_calls`, how far we got is passed on in
+/// an argument after the primop's own `arity`, so that retrying carries on
+/// where it stopped instead of starting over.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn forced<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    arity: usize,
+    elems: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+    mut more: impl FnMut(&Expr<'gc>) -> Result<Option<GcExpr<'gc>>, ErrorKind>,
+) -> Result<Result<Vec<GcExpr<'gc>>, (GcExpr<'gc>, GcEnv<'gc>)>, ErrorKind> {
+    let mut next = match args.get(arity).map(|next| &**next) {
+        Some(Expr::Int(next)) => *next as usize,
+        Some(_) => unreachable!("{} retried without progress", name),
+        None => 0,
+    };
+    while next < elems.len() {
+        let thunk = match peek(elems[next]) {
+            Some(value) => more(&value)?,
+            None => Some(elems[next]),
+        };
+        if let Some(thunk) = thunk {
+            let mut progress = args[..arity].to_vec();
+            progress.push(Gc::allocate(mc, Expr::Int(next as i64)));
+            return Ok(Err(retry(mc, name, &progress, env, stack, thunk)?));
+        }
+        next += 1;
+    }
+    Ok(Ok(elems.iter().map(|elem| peek(*elem).unwrap()).collect()))
+}
+
+/// Force `thunk` and then call the primop again with the same `args`, see
+/// `Need`.
+fn retry<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+    thunk: GcExpr<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    stack.write(mc).push(Cont::RetryCont {
+        name,
+        args: args.to_vec(),
+    });
+    Ok((thunk, env))
+}
+
+/// `f a b` as a thunk.
+fn call<'gc>(mc: MutationContext<'gc, '_>, f: GcExpr<'gc>, args: Vec<GcExpr<'gc>>, env: GcEnv<'gc>) -> GcExpr<'gc> {
+    let arity = args.len();
+    close(mc, Gc::allocate(mc, Expr::App { f, args, arity }), env)
+}
+
+/// Primops that call functions, e.g. `filter`, make all of the calls up
+/// front and then force them in order until `stop` says that's enough. The
+/// calls and how far we got are passed on in arguments after the primop's
+/// own `arity`, so that retrying (see `Need`) carries on where it stopped.
+/// Returns the values of the calls forced so far, or the next step.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn force_calls<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    arity: usize,
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+    make_calls: impl FnOnce() -> Vec<GcExpr<'gc>>,
+    mut stop: impl FnMut(&Expr<'gc>) -> Result<bool, ErrorKind>,
+) -> Result<Result<Vec<GcExpr<'gc>>, (GcExpr<'gc>, GcEnv<'gc>)>, ErrorKind> {
+    let (calls_expr, mut next) = match args[arity..] {
+        [calls, ref next] => match **next {
+            Expr::Int(next) => (calls, next as usize),
+            _ => unreachable!("{} retried without progress", name),
+        },
+        _ => (Gc::allocate(mc, Expr::List(make_calls())), 0),
+    };
+    let calls = list(&calls_expr)?;
+    while next < calls.len() {
+        match peek(calls[next]) {
+            Some(value) => {
+                next += 1;
+                if stop(&value)? {
+                    break;
+                }
+            }
+            None => {
+                let mut progress = args[..arity].to_vec();
+                progress.push(calls_expr);
+                progress.push(Gc::allocate(mc, Expr::Int(next as i64)));
+                return Ok(Err(retry(mc, name, &progress, env, stack, calls[next])?));
+            }
+        }
+    }
+    Ok(Ok(calls[..next].iter().map(|call| peek(*call).unwrap()).collect()))
+}
+
+fn missing_attribute(name: &str, set: &AttrSet) -> ErrorKind {
+    ErrorKind::MissingAttribute {
+        name: name.to_string(),
+        pos: None,
+        suggestions: suggestions(name, set.iter().map(|(n, _)| n)),
+    }
+}
+
+fn not_implemented<'gc>(
+    _: MutationContext<'gc, '_>,
+    name: &'static str,
+    _: &[GcExpr<'gc>],
+    _: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    Err(ErrorKind::NotImplemented { name: name.to_string() })
+}
+
+/// `+` also concatenates strings and paths.
+fn add<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let value = match (&*args[0], &*args[1]) {
+        // `"a" + x` is `"a${x}"`, and so is `{ outPath = "a"; } + x`
+        (Expr::String(_), _) | (Expr::AttrSet(_), _) => Expr::InterpolatedString(args.to_vec()),
+        (Expr::Path(l), Expr::String(r)) | (Expr::Path(l), Expr::Path(r)) => {
+            Expr::Path(canonical_path(&format!("{}{}", l, r)))
+        }
+        (Expr::Path(_), r) => return Err(type_error("a string", r)),
+        _ => return arithmetic_op(mc, name, args, env, stack),
+    };
+    Ok((Gc::allocate(mc, value), env))
+}
+
+fn arithmetic_op<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    Ok((Gc::allocate(mc, arithmetic(name, &args[0], &args[1])?), env))
+}
+
+fn bitwise<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let (l, r) = match (&*args[0], &*args[1]) {
+        (Expr::Int(l), Expr::Int(r)) => (*l, *r),
+        (Expr::Int(_), e) | (e, _) => return Err(type_error("an integer", e)),
+    };
+    let result = match name {
+        "bitAnd" => l & r,
+        "bitOr" => l | r,
+        _ => l ^ r,
+    };
+    Ok((Gc::allocate(mc, Expr::Int(result)), env))
+}
+
+/// The comparison operators and `elem`, which may have to look inside of
+/// lists and sets. See `Cont::CompareCont`.
+fn compare<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let (l, r) = (args[0], args[1]);
+    let comparison = match name {
+        "<" | ">=" => Comparison::LessThan(l, r),
+        ">" | "<=" => Comparison::LessThan(r, l),
+        "==" | "!=" => Comparison::Equal(l, r),
+        _ => {
+            list(&r)?;
+            Comparison::Elem { x: l, list: r, next: 0 }
+        }
+    };
+    compare_all(mc, name, vec![comparison], env, stack)
+}
+
+/// Carry on with the comparisons `name` is waiting for.
+pub(crate) fn compare_all<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'gc str,
+    mut todo: Vec<Comparison<'gc>>,
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match compare_values(mc, &mut todo)? {
+        Need::Value(b) => {
+            let negate = matches!(name, "<=" | ">=" | "!=");
+            Ok((Gc::allocate(mc, Expr::Bool(b != negate)), env))
+        }
+        Need::Force(thunk) => {
+            stack.write(mc).push(Cont::CompareCont { name, todo, env });
+            Ok((thunk, env))
+        }
+    }
+}
+
+fn concat_lists<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match (&*args[0], &*args[1]) {
+        // the elements are closed already and stay unevaluated
+        (Expr::List(l), Expr::List(r)) => {
+            let value = Expr::List(l.iter().chain(r.iter()).cloned().collect());
+            Ok((Gc::allocate(mc, value), env))
+        }
+        (Expr::List(_), r) | (r, _) => Err(type_error("a list", r)),
+    }
+}
+
+fn update<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let (l, r) = (args[0], args[1]);
+    match (&*l, &*r) {
+        // `//` is mostly used to override a few attributes of a big set,
+        // so don't copy anything if one side doesn't add anything.
+        (Expr::AttrSet(_), Expr::AttrSet(attrs)) if attrs.is_empty() => Ok((l, env)),
+        (Expr::AttrSet(attrs), Expr::AttrSet(_)) if attrs.is_empty() => Ok((r, env)),
+        (Expr::AttrSet(left), Expr::AttrSet(right)) => {
+            Ok((Gc::allocate(mc, Expr::AttrSet(left.update(mc, right))), env))
+        }
+        (Expr::AttrSet(_), r) | (r, _) => Err(type_error("a set", r)),
+    }
+}
+
+/// `throw` and `abort`
+fn throw<'gc>(
+    _: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    _: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let message = match *args[0] {
+        Expr::String(ref s) => s.clone(),
+        ref e => return Err(type_error("a string", e)),
+    };
+    Err(match name {
+        "throw" => ErrorKind::Throw { message },
+        _ => ErrorKind::Abort { message },
+    })
+}
+
+fn to_string<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    stack.write(mc).push(Cont::CoerceCont {
+        parts: vec![args[0]],
+        next: 0,
+        done: String::new(),
+        env,
+        more: true,
+    });
+    Ok((args[0], env))
+}
+
+/// `isNull`, `isAttrs`, ...
+fn is_type<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let result = matches!(
+        (name, &*args[0]),
+        ("isNull", Expr::Null())
+            | ("isAttrs", Expr::AttrSet(_))
+            | ("isList", Expr::List(_))
+            | ("isFunction", Expr::Closure { .. } | Expr::PrimOp { .. } | Expr::HostFn(_) | Expr::Pap { .. })
+    );
+    Ok((Gc::allocate(mc, Expr::Bool(result)), env))
+}
+
+/// `map f [ a b ]` is `[ (f a) (f b) ]`, without calling `f` yet.
+fn map<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let elems = match *args[1] {
+        Expr::List(ref elems) => elems,
+        ref e => return Err(type_error("a list", e)),
+    };
+    let elems = elems
+        .iter()
+        .map(|elem| {
+            let call = Expr::App {
+                f: args[0],
+                args: vec![*elem],
+                arity: 1,
+            };
+            close(mc, Gc::allocate(mc, call), env)
+        })
+        .collect();
+    Ok((Gc::allocate(mc, Expr::List(elems)), env))
+}
+
+/// The names of a set, sorted.
+fn attr_names<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let names = set(&args[0])?
+        .iter()
+        .map(|(name, _)| Gc::allocate(mc, Expr::String(name.clone())))
+        .collect();
+    Ok((Gc::allocate(mc, Expr::List(names)), env))
+}
+
+/// The values of a set, in the order of `attrNames`.
+fn attr_values<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let values = set(&args[0])?.iter().map(|(_, value)| *value).collect();
+    Ok((Gc::allocate(mc, Expr::List(values)), env))
+}
+
+fn get_attr<'gc>(
+    _: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let (name, attrs) = (string(&args[0])?, set(&args[1])?);
+    match attrs.get(name) {
+        Some(value) => Ok((value, env)),
+        None => Err(missing_attribute(name, attrs)),
+    }
+}
+
+fn has_attr<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let (name, attrs) = (string(&args[0])?, set(&args[1])?);
+    Ok((Gc::allocate(mc, Expr::Bool(attrs.contains(name))), env))
+}
+
+/// `removeAttrs set [ "a" "b" ]`
+fn remove_attrs<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let attrs = set(&args[0])?;
+    let names = match forced(mc, name, args, 2, list(&args[1])?, env, stack, |_| Ok(None))? {
+        Ok(names) => names,
+        Err(step) => return Ok(step),
+    };
+    let names = names.iter().map(|name| string(name)).collect::<Result<Vec<_>, _>>()?;
+    let mut result = AttrSet::new();
+    for (name, value) in attrs.iter() {
+        if !names.contains(&name.as_str()) {
+            result.insert(mc, name.clone(), *value);
+        }
+    }
+    Ok((Gc::allocate(mc, Expr::AttrSet(result)), env))
+}
+
+/// `listToAttrs [ { name = "a"; value = 1; } ]` is `{ a = 1; }`. The first
+/// of several elements with the same name wins.
+fn list_to_attrs<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    // the names need forcing too
+    let name_of = |elem: &Expr<'gc>| {
+        let attrs = set(elem)?;
+        attrs.get("name").ok_or_else(|| missing_attribute("name", attrs))
+    };
+    let more = |elem: &Expr<'gc>| {
+        let attr_name = name_of(elem)?;
+        Ok(match peek(attr_name) {
+            Some(_) => None,
+            None => Some(attr_name),
+        })
+    };
+    let elems = match forced(mc, name, args, 1, list(&args[0])?, env, stack, more)? {
+        Ok(elems) => elems,
+        Err(step) => return Ok(step),
+    };
+    let mut result = AttrSet::new();
+    for elem in elems {
+        let attrs = set(&elem)?;
+        let attr_name = peek(name_of(&elem)?).unwrap();
+        let attr_name = string(&attr_name)?;
+        // the values stay lazy
+        let value = attrs.get("value").ok_or_else(|| missing_attribute("value", attrs))?;
+        if !result.contains(attr_name) {
+            result.insert(mc, attr_name.to_string(), value);
+        }
+    }
+    Ok((Gc::allocate(mc, Expr::AttrSet(result)), env))
+}
+
+/// The attributes of the second set whose names are in the first one.
+fn intersect_attrs<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let (names, attrs) = (set(&args[0])?, set(&args[1])?);
+    let mut result = AttrSet::new();
+    for (name, value) in attrs.iter() {
+        if names.contains(name) {
+            result.insert(mc, name.clone(), *value);
+        }
+    }
+    Ok((Gc::allocate(mc, Expr::AttrSet(result)), env))
+}
+
+/// `catAttrs "a" [ { a = 1; } { } ]` is `[ 1 ]`.
+fn cat_attrs<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let attr_name = string(&args[0])?;
+    let elems = match forced(mc, name, args, 2, list(&args[1])?, env, stack, |_| Ok(None))? {
+        Ok(elems) => elems,
+        Err(step) => return Ok(step),
+    };
+    let mut values = Vec::new();
+    for elem in elems {
+        if let Some(value) = set(&elem)?.get(attr_name) {
+            values.push(value);
+        }
+    }
+    Ok((Gc::allocate(mc, Expr::List(values)), env))
+}
+
+/// `mapAttrs f { a = 1; }` is `{ a = f "a" 1; }`.
+fn map_attrs<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let mut result = AttrSet::new();
+    for (name, value) in set(&args[1])?.iter() {
+        let name_value = Gc::allocate(mc, Expr::String(name.clone()));
+        result.insert(mc, name.clone(), call(mc, args[0], vec![name_value, *value], env));
+    }
+    Ok((Gc::allocate(mc, Expr::AttrSet(result)), env))
+}
+
+/// `zipAttrsWith f [ { a = 1; } { a = 2; b = 3; } ]` is
+/// `{ a = f "a" [ 1 2 ]; b = f "b" [ 3 ]; }`.
+fn zip_attrs_with<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let elems = match forced(mc, name, args, 2, list(&args[1])?, env, stack, |_| Ok(None))? {
+        Ok(elems) => elems,
+        Err(step) => return Ok(step),
+    };
+    let mut zipped: BTreeMap<&str, Vec<GcExpr<'gc>>> = BTreeMap::new();
+    for elem in &elems {
+        for (name, value) in set(elem)?.iter() {
+            zipped.entry(name).or_default().push(*value);
+        }
+    }
+    let mut result = AttrSet::new();
+    for (name, values) in zipped {
+        let name_value = Gc::allocate(mc, Expr::String(name.to_string()));
+        let values = Gc::allocate(mc, Expr::List(values));
+        result.insert(mc, name.to_string(), call(mc, args[0], vec![name_value, values], env));
+    }
+    Ok((Gc::allocate(mc, Expr::AttrSet(result)), env))
+}
+
+/// The formals of a function, each mapped to whether it has a default.
+/// Builtins and plain `x: ...` lambdas take `{ }`.
+fn function_args<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let mut result = AttrSet::new();
+    match *args[0] {
+        Expr::Closure { lambda, .. } => {
+            if let Expr::Lambda {
+                formals: Some((ref formals, _)),
+                ..
+            } = *lambda
+            {
+                for formal in formals {
+                    if let Expr::Formal(ref name, default) = **formal {
+                        result.insert(mc, name.clone(), Gc::allocate(mc, Expr::Bool(default.is_some())));
+                    }
+                }
+            }
+        }
+        Expr::PrimOp { .. } | Expr::HostFn(_) | Expr::Pap { .. } => {}
+        ref e => return Err(type_error("a function", e)),
+    }
+    Ok((Gc::allocate(mc, Expr::AttrSet(result)), env))
+}
+
+fn length<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    Ok((Gc::allocate(mc, Expr::Int(list(&args[0])?.len() as i64)), env))
+}
+
+fn head<'gc>(
+    _: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match list(&args[0])?.first() {
+        Some(first) => Ok((*first, env)),
+        None => Err(ErrorKind::IndexOutOfBounds { index: 0 }),
+    }
+}
+
+fn tail<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match list(&args[0])? {
+        [] => Err(ErrorKind::IndexOutOfBounds { index: 0 }),
+        [_, rest @ ..] => Ok((Gc::allocate(mc, Expr::List(rest.to_vec())), env)),
+    }
+}
+
+fn elem_at<'gc>(
+    _: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let index = match *args[1] {
+        Expr::Int(index) => index,
+        ref e => return Err(type_error("an integer", e)),
+    };
+    match list(&args[0])?.get(index as usize) {
+        Some(elem) if index >= 0 => Ok((*elem, env)),
+        _ => Err(ErrorKind::IndexOutOfBounds { index }),
+    }
+}
+
+/// `genList f 3` is `[ (f 0) (f 1) (f 2) ]`. Nix has no `replicate`,
+/// nixpkgs' `lib.replicate n x` is `genList (_: x) n`.
+fn gen_list<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    _: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let n = match *args[1] {
+        Expr::Int(n) if n >= 0 => n,
+        Expr::Int(length) => return Err(ErrorKind::NegativeListLength { length }),
+        ref e => return Err(type_error("an integer", e)),
+    };
+    let elems = (0..n)
+        .map(|i| call(mc, args[0], vec![Gc::allocate(mc, Expr::Int(i))], env))
+        .collect();
+    Ok((Gc::allocate(mc, Expr::List(elems)), env))
+}
+
+/// `concatLists [ [ 1 ] [ 2 3 ] ]` is `[ 1 2 3 ]`.
+fn concat_all<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let lists = match forced(mc, name, args, 1, list(&args[0])?, env, stack, |_| Ok(None))? {
+        Ok(lists) => lists,
+        Err(step) => return Ok(step),
+    };
+    let mut elems = Vec::new();
+    for l in &lists {
+        elems.extend_from_slice(list(l)?);
+    }
+    Ok((Gc::allocate(mc, Expr::List(elems)), env))
+}
+
+fn concat_map<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let elems = list(&args[1])?;
+    let make_calls = || elems.iter().map(|x| call(mc, args[0], vec![*x], env)).collect();
+    let lists = match force_calls(mc, name, args, 2, env, stack, make_calls, |l| list(l).map(|_| false))? {
+        Ok(lists) => lists,
+        Err(step) => return Ok(step),
+    };
+    let mut elems = Vec::new();
+    for l in &lists {
+        elems.extend_from_slice(list(l)?);
+    }
+    Ok((Gc::allocate(mc, Expr::List(elems)), env))
+}
+
+/// `filter` and `partition`, which is `filter` that also keeps the rest.
+fn filter<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let elems = list(&args[1])?;
+    let make_calls = || elems.iter().map(|x| call(mc, args[0], vec![*x], env)).collect();
+    let keep = match force_calls(mc, name, args, 2, env, stack, make_calls, |b| bool_value(b).map(|_| false))? {
+        Ok(keep) => keep,
+        Err(step) => return Ok(step),
+    };
+    let (mut right, mut wrong) = (Vec::new(), Vec::new());
+    for (elem, keep) in elems.iter().zip(keep) {
+        match *keep {
+            Expr::Bool(true) => right.push(*elem),
+            _ => wrong.push(*elem),
+        }
+    }
+    let result = match name {
+        "partition" => {
+            let mut result = AttrSet::new();
+            result.insert(mc, "right".to_string(), Gc::allocate(mc, Expr::List(right)));
+            result.insert(mc, "wrong".to_string(), Gc::allocate(mc, Expr::List(wrong)));
+            Expr::AttrSet(result)
+        }
+        _ => Expr::List(right),
+    };
+    Ok((Gc::allocate(mc, result), env))
+}
+
+/// `any` stops at the first `true`, `all` at the first `false`.
+fn any_all<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let elems = list(&args[1])?;
+    let make_calls = || elems.iter().map(|x| call(mc, args[0], vec![*x], env)).collect();
+    let any = name == "any";
+    let results = match force_calls(mc, name, args, 2, env, stack, make_calls, |b| bool_value(b).map(|b| b == any))? {
+        Ok(results) => results,
+        Err(step) => return Ok(step),
+    };
+    // `any` stops at the first `true` and `all` at the first `false`
+    let stopped = results.last().is_some_and(|b| matches!(**b, Expr::Bool(b) if b == any));
+    let result = if any { stopped } else { !stopped };
+    Ok((Gc::allocate(mc, Expr::Bool(result)), env))
+}
+
+/// `foldl' f z [ a b ]` is `f (f z a) b`, evaluating `f z a` first.
+fn foldl<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let elems = list(&args[2])?;
+    let make_calls = || {
+        let mut acc = args[1];
+        let mut calls = Vec::with_capacity(elems.len());
+        for x in elems {
+            acc = call(mc, args[0], vec![acc, *x], env);
+            calls.push(acc);
+        }
+        calls
+    };
+    match force_calls(mc, name, args, 3, env, stack, make_calls, |_| Ok(false))? {
+        Ok(results) => Ok((results.last().cloned().unwrap_or(args[1]), env)),
+        Err(step) => Ok(step),
+    }
+}
+
+/// `groupBy (x: if x > 1 then "big" else "small") [ 1 2 3 ]` is
+/// `{ big = [ 2 3 ]; small = [ 1 ]; }`.
+fn group_by<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let elems = list(&args[1])?;
+    let make_calls = || elems.iter().map(|x| call(mc, args[0], vec![*x], env)).collect();
+    let names = match force_calls(mc, name, args, 2, env, stack, make_calls, |s| string(s).map(|_| false))? {
+        Ok(names) => names,
+        Err(step) => return Ok(step),
+    };
+    let mut groups: BTreeMap<&str, Vec<GcExpr<'gc>>> = BTreeMap::new();
+    for (elem, name) in elems.iter().zip(&names) {
+        groups.entry(string(name)?).or_default().push(*elem);
+    }
+    let mut result = AttrSet::new();
+    for (name, elems) in groups {
+        result.insert(mc, name.to_string(), Gc::allocate(mc, Expr::List(elems)));
+    }
+    Ok((Gc::allocate(mc, Expr::AttrSet(result)), env))
+}
+
+/// `sort f list`, where `f a b` says whether `a` goes before `b`. Elements
+/// that are equal as far as `f` is concerned stay in the order they were in.
+fn sort<'gc>(
+    mc: MutationContext<'gc, '_>,
+    _: &'static str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    merge_runs(mc, args[0], args[1], None, 1, 0, 0, 1, env, stack)
+}
+
+/// Carry on with `sort` once we know if `items[right]` goes first.
+pub(crate) fn merge<'gc>(
+    mc: MutationContext<'gc, '_>,
+    cont: Cont<'gc>,
+    right_first: bool,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match cont {
+        Cont::SortCont {
+            f,
+            items,
+            mut merged,
+            width,
+            start,
+            mut left,
+            mut right,
+            env,
+        } => {
+            let elems = list(&items)?;
+            let elem = if right_first {
+                right += 1;
+                elems[right - 1]
+            } else {
+                left += 1;
+                elems[left - 1]
+            };
+            merged = Some(Gc::allocate(mc, Merged { elem, rest: merged }));
+            merge_runs(mc, f, items, merged, width, start, left, right, env, stack)
+        }
+        _ => unreachable!("merge without SortCont"),
+    }
+}
+
+/// Merge the runs `items[start..start + width]` and the `width` after
+/// that, then the next pair, until a comparison needs evaluating.
+#[allow(clippy::too_many_arguments)]
+fn merge_runs<'gc>(
+    mc: MutationContext<'gc, '_>,
+    f: GcExpr<'gc>,
+    mut items: GcExpr<'gc>,
+    mut merged: Option<Gc<'gc, Merged<'gc>>>,
+    mut width: usize,
+    mut start: usize,
+    mut left: usize,
+    mut right: usize,
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let n = list(&items)?.len();
+    while width < n {
+        let elems = list(&items)?;
+        let middle = (start + width).min(n);
+        let end = (start + 2 * width).min(n);
+        if left < middle && right < end {
+            let compare = Expr::App {
+                f,
+                args: vec![elems[right], elems[left]],
+                arity: 2,
+            };
+            stack.write(mc).push(Cont::SortCont {
+                f,
+                items,
+                merged,
+                width,
+                start,
+                left,
+                right,
+                env,
+            });
+            return Ok((Gc::allocate(mc, compare), env));
+        }
+        // one of the runs is done, the rest of the other one goes after it
+        for &elem in elems[left..middle].iter().chain(&elems[right.max(middle)..end]) {
+            merged = Some(Gc::allocate(mc, Merged { elem, rest: merged }));
+        }
+        start = end;
+        if start >= n {
+            let mut sorted = Vec::with_capacity(n);
+            while let Some(m) = merged {
+                sorted.push(m.elem);
+                merged = m.rest;
+            }
+            sorted.reverse();
+            items = Gc::allocate(mc, Expr::List(sorted));
+            start = 0;
+            width *= 2;
+        }
+        left = start;
+        right = (start + width).min(n);
+    }
+    Ok((items, env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::tests::{eval_bool, eval_int, eval_strict, lang_test};
+
+    #[test]
+    fn check_primops() {
+        assert_eq!(eval_strict("map (x: x * 2) [ 1 2 3 ]").unwrap(), "[ 2 4 6 ]");
+        assert_eq!(eval_strict("builtins.map (x: x) [ ]").unwrap(), "[ ]");
+        // the function and the elements are only evaluated when needed
+        assert_eq!(eval_strict("map undefined [ ]").unwrap(), "[ ]");
+        assert_eq!(eval_int("let xs = map (x: 10 / x) [ 0 5 ]; in builtins.elem 2 xs"), Err(ErrorKind::DivisionByZero));
+        assert_eq!(eval_strict("map (x: 1) [ undefined ]").unwrap(), "[ 1 ]");
+        assert_eq!(eval_bool("isNull null && !(builtins.isNull 1)"), Ok(true));
+        assert_eq!(eval_int("builtins.add 1 (builtins.mul 2 3)"), Ok(7));
+        // CALLK, the extra argument goes to whatever `map` returns
+        assert_eq!(
+            eval_strict("map (x: y: x + y) [ 1 ] 2"),
+            Err(ErrorKind::TypeError {
+                expected: "a function",
+                got: "a list"
+            })
+        );
+        assert_eq!(
+            eval_strict("map 1 2"),
+            Err(ErrorKind::TypeError {
+                expected: "a list",
+                got: "an integer"
+            })
+        );
+        assert!(primop("map").is_some_and(|op| op.arity == 2 && !op.is_strict(0) && op.is_strict(1)));
+        assert!(primop("frobnicate").is_none());
+    }
+
+    #[test]
+    fn check_attr_builtins() {
+        for name in &["attrnames", "listtoattrs", "mapattrs", "catattrs", "remove"] {
+            lang_test(name);
+        }
+        // nix only has an expected `--xml` output for this one
+        assert_eq!(
+            eval_strict(include_str!("lang-tests/eval-okay-functionargs.nix")).unwrap(),
+            concat!(
+                r#"[ "stdenv" "fetchurl" "aterm-stdenv" "aterm-stdenv2" "libX11" "libXv" "#,
+                r#""mplayer-stdenv2.libXv-libX11" "mplayer-stdenv2.libXv-libX11_2" "#,
+                r#""nix-stdenv-aterm-stdenv" "nix-stdenv2-aterm2-stdenv2" ]"#
+            )
+        );
+
+        assert_eq!(eval_strict("builtins.attrValues { b = 2; a = 1; }").unwrap(), "[ 1 2 ]");
+        assert_eq!(eval_int("builtins.getAttr \"a\" { a = 1; }"), Ok(1));
+        assert_eq!(
+            eval_int("builtins.getAttr \"b\" { a = 1; }"),
+            Err(ErrorKind::MissingAttribute {
+                name: "b".to_string(),
+                pos: None,
+                suggestions: vec!["a".to_string()],
+            })
+        );
+        assert_eq!(eval_bool("builtins.hasAttr \"a\" { a = undefined; }"), Ok(true));
+        assert_eq!(eval_strict("removeAttrs { a = 1; b = 2; c = 3; } [ \"a\" \"c\" \"d\" ]").unwrap(), "{ b = 2; }");
+        assert_eq!(
+            eval_strict("builtins.intersectAttrs { a = undefined; b = 1; } { b = 2; c = 3; }").unwrap(),
+            "{ b = 2; }"
+        );
+        assert_eq!(
+            eval_strict("builtins.zipAttrsWith (name: values: values) [ { a = 1; } { a = 2; b = 3; } ]").unwrap(),
+            "{ a = [ 1 2 ]; b = [ 3 ]; }"
+        );
+        assert_eq!(eval_strict("builtins.zipAttrsWith undefined [ ]").unwrap(), "{ }");
+        assert_eq!(
+            eval_strict("builtins.functionArgs ({ a, b ? 1, ... }: a)").unwrap(),
+            "{ a = false; b = true; }"
+        );
+        assert_eq!(eval_strict("builtins.functionArgs (x: x)").unwrap(), "{ }");
+        assert_eq!(eval_strict("builtins.functionArgs map").unwrap(), "{ }");
+        assert_eq!(
+            eval_strict("with builtins; [ (isAttrs { }) (isAttrs [ ]) (isFunction map) (isFunction 1) ]").unwrap(),
+            "[ true false true false ]"
+        );
+        // values aren't forced
+        assert_eq!(eval_bool("builtins.mapAttrs (n: v: undefined) { a = 1; } ? a"), Ok(true));
+        assert_eq!(eval_bool("builtins.listToAttrs [ { name = \"a\"; value = undefined; } ] ? a"), Ok(true));
+    }
+
+    #[test]
+    fn check_list_builtins() {
+        for name in &["map", "filter", "sort", "partition", "flatten", "concatmap", "any-all", "elem"] {
+            lang_test(name);
+        }
+
+        assert_eq!(eval_int("builtins.length [ undefined undefined ]"), Ok(2));
+        assert_eq!(eval_int("builtins.head [ 1 undefined ]"), Ok(1));
+        assert_eq!(eval_strict("builtins.tail [ undefined 2 ]").unwrap(), "[ 2 ]");
+        assert_eq!(eval_int("builtins.head [ ]"), Err(ErrorKind::IndexOutOfBounds { index: 0 }));
+        assert_eq!(eval_int("builtins.elemAt [ 1 ] 1"), Err(ErrorKind::IndexOutOfBounds { index: 1 }));
+        assert_eq!(eval_int("builtins.elemAt [ 1 ] (-1)"), Err(ErrorKind::IndexOutOfBounds { index: -1 }));
+        assert_eq!(eval_int("builtins.foldl' (acc: x: acc * 10 + x) 0 [ 1 2 3 ]"), Ok(123));
+        assert_eq!(eval_int("builtins.foldl' undefined 7 [ ]"), Ok(7));
+        assert_eq!(eval_int("let sum = builtins.foldl' builtins.add 0; in sum [ 1 2 3 ]"), Ok(6));
+        assert_eq!(eval_strict("builtins.genList (x: x * x) 4").unwrap(), "[ 0 1 4 9 ]");
+        assert_eq!(
+            eval_strict("builtins.groupBy (x: if x > 1 then \"big\" else \"small\") [ 1 2 3 ]").unwrap(),
+            r#"{ big = [ 2 3 ]; small = [ 1 ]; }"#
+        );
+        assert_eq!(
+            eval_strict("with builtins; [ (isList [ ]) (isList { }) (sort lessThan [ ]) (concatLists [ ]) ]").unwrap(),
+            "[ true false [ ] [ ] ]"
+        );
+        // stable, even for more than one pass of merging
+        assert_eq!(
+            eval_strict("builtins.sort (a: b: a / 10 < b / 10) [ 31 12 23 11 32 21 13 22 33 ]").unwrap(),
+            "[ 12 11 13 23 21 22 31 32 33 ]"
+        );
+        // elements aren't forced unless they have to be
+        assert_eq!(eval_int("builtins.length (map undefined [ 1 2 ])"), Ok(2));
+        assert_eq!(eval_int("builtins.length (builtins.genList undefined 3)"), Ok(3));
+        assert_eq!(
+            eval_strict("builtins.genList (x: x) (-1)").unwrap_err().to_string(),
+            "cannot create a list of negative length (-1)"
+        );
+        assert_eq!(eval_int("builtins.length (builtins.filter (x: true) [ undefined ])"), Ok(1));
+        assert_eq!(eval_int("builtins.length (builtins.partition (x: false) [ undefined ]).wrong"), Ok(1));
+        assert_eq!(eval_bool("builtins.any (x: x) [ true undefined ]"), Ok(true));
+        assert_eq!(eval_bool("builtins.all (x: x) [ false undefined ]"), Ok(false));
+    }
+}
@@ -0,0 +1,335 @@
+//! Reading evaluated nix values into Rust types with serde, e.g.
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct Config { name: String, port: u16 }
+//! let config: Config = from_expr(mc, eval(mc, expr, 10_000)?, 10_000)?;
+//! ```
+//!
+//! Values are only forced as far as the Rust type asks for them, so
+//! attributes the type doesn't have are never evaluated.
+use crate::builtins;
+use crate::eval::{eval_in, EvalError};
+use crate::expr::{Expr, GcEnv, GcExpr};
+use gc_arena::MutationContext;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Unexpected, Visitor};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Forcing a value failed.
+    Eval(EvalError),
+    /// The value doesn't fit the Rust type.
+    Message(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Eval(e) => write!(f, "{}", e),
+            Error::Message(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Error {
+        Error::Message(message.to_string())
+    }
+}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Error {
+        Error::Message(message.to_string())
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Error {
+        Error::Eval(e)
+    }
+}
+
+/// Deserialize `T` from `expr`, taking at most `max_steps` steps for each
+/// value that needs forcing.
+pub fn from_expr<'gc, T: DeserializeOwned>(
+    mc: MutationContext<'gc, '_>,
+    expr: GcExpr<'gc>,
+    max_steps: usize,
+) -> Result<T, Error> {
+    T::deserialize(Deserializer::new(mc, expr, max_steps))
+}
+
+#[derive(Clone, Copy)]
+pub struct Deserializer<'gc, 'a> {
+    mc: MutationContext<'gc, 'a>,
+    expr: GcExpr<'gc>,
+    env: GcEnv<'gc>,
+    max_steps: usize,
+}
+
+impl<'gc, 'a> Deserializer<'gc, 'a> {
+    pub fn new(mc: MutationContext<'gc, 'a>, expr: GcExpr<'gc>, max_steps: usize) -> Deserializer<'gc, 'a> {
+        Deserializer {
+            mc,
+            expr,
+            env: builtins::root_env(mc),
+            max_steps,
+        }
+    }
+
+    /// The same, for a value inside of this one.
+    fn nested(&self, expr: GcExpr<'gc>) -> Deserializer<'gc, 'a> {
+        Deserializer { expr, ..*self }
+    }
+
+    fn force(&self) -> Result<GcExpr<'gc>, Error> {
+        if self.expr.is_value() {
+            return Ok(self.expr);
+        }
+        Ok(eval_in(self.mc, self.expr, self.env, self.max_steps)?)
+    }
+}
+
+impl<'de, 'gc, 'a> de::Deserializer<'de> for Deserializer<'gc, 'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let value = self.force()?;
+        match *value {
+            Expr::Null() => visitor.visit_unit(),
+            Expr::Bool(b) => visitor.visit_bool(b),
+            Expr::Int(i) => visitor.visit_i64(i),
+            Expr::Float(x) => visitor.visit_f64(x),
+            Expr::String(ref s) | Expr::Path(ref s) => visitor.visit_string(s.clone()),
+            Expr::List(ref elems) => visitor.visit_seq(SeqAccess {
+                de: self,
+                elems: elems.clone().into_iter(),
+            }),
+            Expr::AttrSet(ref attrs) => visitor.visit_map(MapAccess {
+                de: self,
+                attrs: attrs.iter().map(|(name, value)| (name.clone(), *value)).collect::<Vec<_>>().into_iter(),
+                value: None,
+            }),
+            ref e => Err(de::Error::invalid_type(Unexpected::Other(e.type_name()), &visitor)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match *self.force()? {
+            Expr::Null() => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Enums are either a string, for variants without data, or a set with
+    /// one attribute, i.e. `{ Tcp = { port = 80; }; }`.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let value = self.force()?;
+        match *value {
+            Expr::String(ref s) => visitor.visit_enum(s.clone().into_deserializer()),
+            Expr::AttrSet(ref attrs) if attrs.len() == 1 => {
+                let (variant, value) = attrs.iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    de: self.nested(*value),
+                    variant: variant.clone(),
+                })
+            }
+            ref e => {
+                let expected = "a string or a set with one attribute";
+                Err(de::Error::invalid_type(Unexpected::Other(e.type_name()), &expected))
+            }
+        }
+    }
+
+    /// Whatever is ignored doesn't get evaluated.
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct SeqAccess<'gc, 'a> {
+    de: Deserializer<'gc, 'a>,
+    elems: std::vec::IntoIter<GcExpr<'gc>>,
+}
+
+impl<'de, 'gc, 'a> de::SeqAccess<'de> for SeqAccess<'gc, 'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        match self.elems.next() {
+            Some(elem) => seed.deserialize(self.de.nested(elem)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.elems.len())
+    }
+}
+
+struct MapAccess<'gc, 'a> {
+    de: Deserializer<'gc, 'a>,
+    attrs: std::vec::IntoIter<(String, GcExpr<'gc>)>,
+    // the value of the name `next_key_seed` returned last
+    value: Option<GcExpr<'gc>>,
+}
+
+impl<'de, 'gc, 'a> de::MapAccess<'de> for MapAccess<'gc, 'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        match self.attrs.next() {
+            Some((name, value)) => {
+                self.value = Some(value);
+                seed.deserialize(name.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self.value.take().expect("next_value_seed before next_key_seed");
+        seed.deserialize(self.de.nested(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.attrs.len())
+    }
+}
+
+struct EnumAccess<'gc, 'a> {
+    de: Deserializer<'gc, 'a>,
+    variant: String,
+}
+
+impl<'de, 'gc, 'a> de::EnumAccess<'de> for EnumAccess<'gc, 'a> {
+    type Error = Error;
+    type Variant = Deserializer<'gc, 'a>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self::Variant), Error> {
+        let name: de::value::StringDeserializer<Error> = self.variant.into_deserializer();
+        let variant = seed.deserialize(name)?;
+        Ok((variant, self.de))
+    }
+}
+
+impl<'de, 'gc, 'a> de::VariantAccess<'de> for Deserializer<'gc, 'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{eval, ErrorKind};
+    use crate::expr_parser::exprParser;
+    use crate::lexer::nix_lexer::Lexer;
+    use gc_arena::rootless_arena;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Config {
+        name: String,
+        port: u16,
+        ratio: f64,
+        tags: Vec<String>,
+        backup: Option<Box<Config>>,
+        protocol: Protocol,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Protocol {
+        Udp,
+        Tcp { keepalive: bool },
+    }
+
+    fn from_nix<T: DeserializeOwned>(src: &str) -> Result<T, Error> {
+        rootless_arena(|mc| {
+            let expr = exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap();
+            from_expr(mc, eval(mc, expr, 1000)?, 1000)
+        })
+    }
+
+    #[test]
+    fn check_deserialize() {
+        let src = r#"
+            let base = { name = "a"; port = 80; ratio = 1; tags = [ ]; backup = null; protocol = "Udp"; };
+            in base // {
+              tags = map (x: "tag-${x}") [ "x" "y" ];
+              backup = base // { name = "b"; port = base.port + 1; };
+              protocol.Tcp.keepalive = base.backup != null;
+              # never evaluated, it's not part of `Config`
+              unused = throw "boom";
+            }
+        "#;
+        let backup = Config {
+            name: "b".to_string(),
+            port: 81,
+            ratio: 1.0,
+            tags: vec![],
+            backup: None,
+            protocol: Protocol::Udp,
+        };
+        assert_eq!(
+            from_nix::<Config>(src),
+            Ok(Config {
+                name: "a".to_string(),
+                port: 80,
+                ratio: 1.0,
+                tags: vec!["tag-x".to_string(), "tag-y".to_string()],
+                backup: Some(Box::new(backup)),
+                protocol: Protocol::Tcp { keepalive: false },
+            })
+        );
+
+        assert_eq!(
+            from_nix::<BTreeMap<String, (i64, bool)>>("{ b = [ 2 false ]; a = [ 1 true ]; }").unwrap(),
+            vec![("a".to_string(), (1, true)), ("b".to_string(), (2, false))].into_iter().collect()
+        );
+        assert_eq!(from_nix::<Option<i64>>("null"), Ok(None));
+        assert!(matches!(from_nix::<u8>("256"), Err(Error::Message(_))));
+        assert!(matches!(from_nix::<String>("x: x"), Err(Error::Message(_))));
+        assert_eq!(
+            from_nix::<Vec<i64>>("[ 1 (throw \"boom\") ]").map_err(|e| match e {
+                Error::Eval(e) => e.kind,
+                e => panic!("{}", e),
+            }),
+            Err(ErrorKind::Throw {
+                message: "boom".to_string()
+            })
+        );
+    }
+}
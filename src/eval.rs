@@ -1,6 +1,199 @@
-use crate::expr::{Cont, Env, Expr, ExprArena, ExprRoot, GcEnv, GcExpr, GcStack};
-use gc_arena::{make_arena, ArenaParameters, Collect, Gc, GcCell, MutationContext};
+use crate::builtins;
+use crate::expr::{
+    float_to_string, AttrSet, Comparison, Cont, Env, Expr, ExprArena, ExprRoot, GcEnv, GcExpr, GcStack, Pos, ThunkState,
+};
+use crate::host::{Host, HostFn, Value};
+use gc_arena::{ArenaParameters, Gc, GcCell, MutationContext};
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+/// What went wrong, see `EvalError` for where.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    UndefinedVariable { name: String, pos: Pos },
+    TypeError { expected: &'static str, got: &'static str },
+    MissingArgument { name: String, pos: Pos },
+    UnexpectedArgument { name: String, pos: Pos },
+    MissingAttribute {
+        name: String,
+        // `None` for builtins like `getAttr`
+        pos: Option<Pos>,
+        // "did you mean ...", best match first
+        suggestions: Vec<String>,
+    },
+    DuplicateAttribute { name: String, pos: Pos },
+    AssertionFailed { pos: Pos },
+    DivisionByZero,
+    /// `elemAt`, `head` or `tail` past the end of a list.
+    IndexOutOfBounds { index: i64 },
+    /// `genList f n` with a negative `n`.
+    NegativeListLength { length: i64 },
+    IntegerOverflow { op: String, left: i64, right: i64 },
+    CannotCompare { left: &'static str, right: &'static str },
+    InfiniteRecursion { pos: Option<Pos> },
+    CannotCoerce { got: &'static str },
+    /// Interpolating a path that doesn't exist.
+    CannotCopyToStore { path: String, error: String },
+    /// `throw "message"`
+    Throw { message: String },
+    /// `abort "message"`
+    Abort { message: String },
+    /// `eval` gave up after `max_steps` steps.
+    StepLimit { max_steps: usize },
+    /// Someone set `Evaluation::interrupt`.
+    Interrupted,
+    /// A builtin we know the name of but don't implement (yet).
+    NotImplemented { name: String },
+}
+
+impl ErrorKind {
+    /// Where the error happened, if the error itself knows.
+    pub fn pos(&self) -> Option<Pos> {
+        match self {
+            ErrorKind::UndefinedVariable { pos, .. }
+            | ErrorKind::MissingArgument { pos, .. }
+            | ErrorKind::UnexpectedArgument { pos, .. }
+            | ErrorKind::DuplicateAttribute { pos, .. }
+            | ErrorKind::AssertionFailed { pos } => Some(*pos),
+            ErrorKind::InfiniteRecursion { pos } | ErrorKind::MissingAttribute { pos, .. } => *pos,
+            _ => None,
+        }
+    }
+}
+
+/// An error `eval` ran into, together with a nix-level stack trace of what
+/// it was in the middle of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalError {
+    pub kind: ErrorKind,
+    /// `kind`'s own position, or otherwise the innermost one in `trace`.
+    pub pos: Option<Pos>,
+    /// Innermost first.
+    pub trace: Vec<TraceFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// e.g. "selecting an attribute"
+    pub what: String,
+    pub pos: Option<Pos>,
+}
+
+impl EvalError {
+    fn new(kind: ErrorKind, stack: &[Cont]) -> EvalError {
+        let trace: Vec<TraceFrame> = stack.iter().rev().filter_map(TraceFrame::new).collect();
+        let pos = kind.pos().or_else(|| trace.iter().find_map(|frame| frame.pos));
+        EvalError { kind, pos, trace }
+    }
+}
+
+impl TraceFrame {
+    /// What a continuation on the stack is waiting for. Not all of them are
+    /// interesting, e.g. an `UpdateCont` doesn't know where its thunk is.
+    fn new(cont: &Cont) -> Option<TraceFrame> {
+        let (what, pos) = match cont {
+            Cont::ApplyCont { .. } => ("evaluating the function to call".to_string(), None),
+            Cont::FormalsCont { lambda, .. } => match **lambda {
+                Expr::Lambda { pos, .. } => ("calling the function".to_string(), Some(pos)),
+                _ => ("calling a function".to_string(), None),
+            },
+            Cont::ForceAppCont { f, .. } => match **f {
+                Expr::PrimOp { name, .. } => (format!("evaluating the arguments of '{}'", name), None),
+                Expr::HostFn(ref host) => (format!("evaluating the arguments of '{}'", host.name), None),
+                _ => ("evaluating the arguments of a function".to_string(), None),
+            },
+            Cont::DynamicAttrsCont { attrs, index, .. } => match **attrs {
+                Expr::Attrs { ref dynamic, .. } => {
+                    ("evaluating a dynamic attribute name".to_string(), Some(dynamic[*index].2))
+                }
+                _ => ("evaluating a dynamic attribute name".to_string(), None),
+            },
+            Cont::IfCont { .. } => ("evaluating the condition of an if".to_string(), None),
+            Cont::AssertCont { pos, .. } => ("evaluating the condition of an assertion".to_string(), Some(*pos)),
+            Cont::BoolOpCont { op, .. } => (format!("evaluating the left side of '{}'", op), None),
+            Cont::RetryCont { name, .. } | Cont::CompareCont { name, .. } => (format!("evaluating '{}'", name), None),
+            Cont::CoerceCont { .. } => ("coercing a value to a string".to_string(), None),
+            Cont::SortCont { .. } => ("comparing two elements in 'sort'".to_string(), None),
+            Cont::SelectCont { pos, .. } | Cont::SelectKeyCont { pos, .. } => {
+                ("selecting an attribute".to_string(), Some(*pos))
+            }
+            Cont::WithCont { name, pos, .. } => (format!("looking up '{}' in a with", name), Some(*pos)),
+            Cont::HasAttrCont { .. } | Cont::HasAttrKeyCont { .. } => ("checking for an attribute".to_string(), None),
+            Cont::UpdateCont { .. } | Cont::BoolCont { .. } => return None,
+        };
+        Some(TraceFrame { what, pos })
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for frame in &self.trace {
+            write!(f, "\n  while {}", frame.what)?;
+            if let Some(pos) = frame.pos {
+                write!(f, " at {}", pos)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UndefinedVariable { name, pos } => write!(f, "undefined variable '{}' at {}", name, pos),
+            ErrorKind::TypeError { expected, got } => write!(f, "value is {} while {} was expected", got, expected),
+            ErrorKind::MissingArgument { name, pos } => {
+                write!(f, "function at {} called without required argument '{}'", pos, name)
+            }
+            ErrorKind::UnexpectedArgument { name, pos } => {
+                write!(f, "function at {} called with unexpected argument '{}'", pos, name)
+            }
+            ErrorKind::MissingAttribute { name, pos, suggestions } => {
+                write!(f, "attribute '{}' missing", name)?;
+                if let Some(pos) = pos {
+                    write!(f, " at {}", pos)?;
+                }
+                match suggestions.as_slice() {
+                    [] => Ok(()),
+                    [one] => write!(f, ", did you mean '{}'?", one),
+                    _ => write!(f, ", did you mean one of '{}'?", suggestions.join("', '")),
+                }
+            }
+            ErrorKind::DuplicateAttribute { name, pos } => {
+                write!(f, "dynamic attribute '{}' at {} already defined", name, pos)
+            }
+            ErrorKind::AssertionFailed { pos } => write!(f, "assertion failed at {}", pos),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::IndexOutOfBounds { index } => write!(f, "list index {} is out of bounds", index),
+            ErrorKind::NegativeListLength { length } => {
+                write!(f, "cannot create a list of negative length ({})", length)
+            }
+            ErrorKind::IntegerOverflow { op, left, right } => {
+                write!(f, "integer overflow in {} {} {}", left, op, right)
+            }
+            ErrorKind::CannotCompare { left, right } => write!(f, "cannot compare {} with {}", left, right),
+            ErrorKind::CannotCoerce { got } => write!(f, "cannot coerce {} to a string", got),
+            ErrorKind::CannotCopyToStore { path, error } => write!(f, "cannot copy '{}' to the store: {}", path, error),
+            ErrorKind::InfiniteRecursion { pos: Some(pos) } => write!(f, "infinite recursion encountered at {}", pos),
+            ErrorKind::InfiniteRecursion { pos: None } => write!(f, "infinite recursion encountered"),
+            ErrorKind::Throw { message } => write!(f, "{}", message),
+            ErrorKind::Abort { message } => {
+                write!(f, "evaluation aborted with the following error message: '{}'", message)
+            }
+            ErrorKind::Interrupted => write!(f, "evaluation interrupted"),
+            ErrorKind::StepLimit { max_steps } => write!(f, "evaluation did not finish in {} steps", max_steps),
+            ErrorKind::NotImplemented { name } => write!(f, "builtin '{}' is not implemented", name),
+        }
+    }
+}
+
+impl std::error::Error for ErrorKind {}
 
 /// The step function is quite large. I might split out some of the braches into
 /// their own functions.
@@ -8,13 +201,8 @@ fn step<'gc>(
     mc: MutationContext<'gc, '_>,
     expr: GcExpr<'gc>,
     env: GcEnv<'gc>,
-    _black_hole: GcExpr<'gc>,
     stack: GcStack<'gc>,
-) -> (GcExpr<'gc>, GcEnv<'gc>) {
-    println!("\n");
-    println!("step {:?}", *expr);
-    println!("   s {:?}", stack.read());
-
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
     let stack_top = stack.read().last().cloned();
 
     match (&*expr, stack_top) {
@@ -29,127 +217,214 @@ fn step<'gc>(
                 f,
                 mut unforced_args,
                 mut forced_args,
+                env: args_env,
             }),
         ) if e.is_value() => {
             stack.write(mc).pop();
-            let popped = unforced_args.pop().unwrap();
+            unforced_args.pop().unwrap();
             forced_args.push(expr);
-            if unforced_args.len() > 0 {
-                let cont = Cont::ForceAppCont {
-                    f: f,
-                    unforced_args: unforced_args.clone(),
-                    forced_args: forced_args,
-                };
-                stack.write(mc).push(cont);
-                return (*unforced_args.last().unwrap(), env);
-            } else {
-                return (
-                    Gc::allocate(
-                        mc,
-                        Expr::App {
-                            f: f,
-                            arity: forced_args.len(),
-                            args: forced_args,
-                        },
-                    ),
-                    env,
-                );
+            Ok(force_args(mc, f, unforced_args, forced_args, args_env, stack))
+        }
+        // The thunk we were forcing has been reduced to a value, overwrite it
+        // so nobody has to evaluate it again.
+        (e, Some(Cont::UpdateCont { t })) if e.is_value() => {
+            stack.write(mc).pop();
+            *t.write(mc) = ThunkState::Value(expr);
+            Ok((expr, env))
+        }
+        (Expr::Let { bindings, body }, _) => {
+            // create chained environment, return (env, body); any expression
+            // in a let binding becomes a thunk with an update, when stepping
+            // into we push UpdateCont for the allocation, and when value +
+            // UpdateCont we update.
+            Ok((*body, Env::new_rec(mc, env, bindings.clone())))
+        }
+        (Expr::Var(name, pos), _) | (Expr::InheritedVar(name, pos), _) => match env.lookup(name) {
+            // the thunk would fail as well, but here we know where we are
+            Some(value) if matches!(*value, Expr::Thunk { t } if matches!(*t.read(), ThunkState::Blackhole { .. })) => {
+                Err(ErrorKind::InfiniteRecursion { pos: Some(*pos) })
+            }
+            Some(value) => Ok((value, env)),
+            None => lookup_with(mc, Some(env), name, *pos, stack),
+        },
+        (Expr::With { expr: set, body }, _) => {
+            let set = close(mc, *set, env);
+            Ok((*body, Gc::allocate(mc, Env::new_with(env, set))))
+        }
+        (e, Some(Cont::WithCont { name, pos, scope })) if e.is_value() => {
+            stack.write(mc).pop();
+            match *e {
+                Expr::AttrSet(ref attrs) => match attrs.get(&name) {
+                    Some(value) => Ok((value, scope)),
+                    None => lookup_with(mc, scope.up(), &name, pos, stack),
+                },
+                _ => Err(ErrorKind::TypeError {
+                    expected: "a set",
+                    got: e.type_name(),
+                }),
+            }
+        }
+        // The boolean operators only evaluate their right side if needed, so
+        // they can't go through ForceAppCont like other binary operators.
+        (Expr::App { f, args, .. }, _) if matches!(**f, Expr::PrimOp { name: "&&" | "||" | "->", .. }) => {
+            let op = match **f {
+                Expr::PrimOp { name, .. } => name,
+                _ => unreachable!(),
+            };
+            stack.write(mc).push(Cont::BoolOpCont { op, right: args[1], env });
+            Ok((args[0], env))
+        }
+        (e, Some(Cont::BoolOpCont { op, right, env })) if e.is_value() => {
+            stack.write(mc).pop();
+            let left = bool_value(e)?;
+            match (op, left) {
+                ("&&", false) => Ok((expr, env)),
+                ("||", true) => Ok((expr, env)),
+                ("->", false) => Ok((Gc::allocate(mc, Expr::Bool(true)), env)),
+                _ => {
+                    stack.write(mc).push(Cont::BoolCont { negate: false });
+                    Ok((right, env))
+                }
+            }
+        }
+        (Expr::UnaryNot { expr }, _) => {
+            stack.write(mc).push(Cont::BoolCont { negate: true });
+            Ok((*expr, env))
+        }
+        (e, Some(Cont::BoolCont { negate })) if e.is_value() => {
+            stack.write(mc).pop();
+            match (bool_value(e)?, negate) {
+                (_, false) => Ok((expr, env)),
+                (b, true) => Ok((Gc::allocate(mc, Expr::Bool(!b)), env)),
+            }
+        }
+        (
+            Expr::IfThenElse {
+                if_expr,
+                then_expr,
+                else_expr,
+            },
+            _,
+        ) => {
+            stack.write(mc).push(Cont::IfCont {
+                then_expr: *then_expr,
+                else_expr: *else_expr,
+                env,
+            });
+            Ok((*if_expr, env))
+        }
+        (
+            e,
+            Some(Cont::IfCont {
+                then_expr,
+                else_expr,
+                env,
+            }),
+        ) if e.is_value() => {
+            stack.write(mc).pop();
+            match bool_value(e)? {
+                true => Ok((then_expr, env)),
+                false => Ok((else_expr, env)),
+            }
+        }
+        (Expr::Assert { expr: cond, body, pos }, _) => {
+            stack.write(mc).push(Cont::AssertCont {
+                body: *body,
+                env,
+                pos: *pos,
+            });
+            Ok((*cond, env))
+        }
+        (e, Some(Cont::AssertCont { body, env, pos })) if e.is_value() => {
+            stack.write(mc).pop();
+            match bool_value(e)? {
+                true => Ok((body, env)),
+                false => Err(ErrorKind::AssertionFailed { pos }),
             }
         }
-        // (Expr::Let { bindings, in_ }, _) => {
-        // create chained environment, return (env, in_); any expression in
-        // a let binding becomes a thunk with an update, when stepping into
-        // we push UpdateCont for the allocation, and when value +
-        // UpdateCont we update.
-
-        // NB that let shares the binding environment (recursive):
-        // nix-repl> let a = b; b = 10; in a
-        // 10
-        // nix-repl> let b = 10; a = b; in a
-        // 10
-        // }
         (Expr::App { f, args, arity, .. }, _) => {
             // TODO only push ApplyCont if either arity mismatch _or_ f is not
             // pointing to a Lambda or PrimOp yet
-            println!("app {:?}", **f);
             match **f {
-                Expr::PrimOp {
-                    arity: op_arity, name, ..
-                } => {
-                    match op_arity.cmp(&arity) {
+                Expr::PrimOp { arity: op_arity, .. } | Expr::HostFn(HostFn { arity: op_arity, .. }) => {
+                    match op_arity.cmp(arity) {
                         // apply `arity` arguments to primop, push new applycont with
                         // remaining args
                         Ordering::Less => {
-                            // rule CALLK
-                            unreachable!("execute primop and push new application cont")
+                            // rule CALLK, e.g. `builtins.getAttr "f" set 1`.
+                            // The extra args go to whatever the primop returns.
+                            stack.write(mc).push(Cont::ApplyCont {
+                                args: args[op_arity..].iter().map(|a| close(mc, *a, env)).collect(),
+                                arity: arity - op_arity,
+                                env,
+                            });
+                            let call = Expr::App {
+                                f: *f,
+                                args: args[..op_arity].to_vec(),
+                                arity: op_arity,
+                            };
+                            Ok((Gc::allocate(mc, call), env))
                         }
                         Ordering::Equal => {
-                            // rule EXACT
-                            let l = (*args)[0];
-                            let r = (*args)[1];
-
-                            // for arg in args.iter() {
-                            //     println!("arg: {:?}, is_value: {}", **arg, arg.is_value());
-                            // }
-
-                            if !args.iter().all(|a| a.is_value()) {
-                                let unforced_args = (*args).clone().into_iter().rev().collect();
-                                let cont = Cont::ForceAppCont {
-                                    f: *f,
-                                    unforced_args: unforced_args,
-                                    forced_args: vec![],
+                            // rule EXACT. Arguments the primop is lazy in are
+                            // passed on as thunks, the others get evaluated.
+                            let ready = args
+                                .iter()
+                                .enumerate()
+                                .all(|(i, a)| a.is_value() || (!strict_arg(f, i) && a.is_closed()));
+                            if ready {
+                                return match **f {
+                                    Expr::HostFn(ref host) => Ok((host.call(mc, args)?, env)),
+                                    Expr::PrimOp { name, .. } => apply_primop(mc, name, args, env, stack),
+                                    _ => unreachable!(),
                                 };
-                                stack.write(mc).push(cont);
-                                return (l, env);
-                            }
-
-                            match (name, &*l, &*r) {
-                                ("+", Expr::Int(left), Expr::Int(right)) => {
-                                    let expr2 = Gc::allocate(mc, Expr::Int(left + right));
-                                    (expr2, env)
-                                }
-                                ("*", Expr::Int(left), Expr::Int(right)) => {
-                                    let expr2 = Gc::allocate(mc, Expr::Int(left * right));
-                                    (expr2, env)
-                                }
-                                _ => unreachable!("invalid op {}/{}", name, arity),
                             }
+                            let unforced_args = args
+                                .iter()
+                                .enumerate()
+                                .rev()
+                                .map(|(i, a)| if strict_arg(f, i) { *a } else { close(mc, *a, env) })
+                                .collect();
+                            Ok(force_args(mc, *f, unforced_args, vec![], env, stack))
                         }
                         Ordering::Greater => {
                             // rule PAP
+                            // the args get evaluated much later, in whatever
+                            // env is current then, so close over this one now.
                             let expr2 = Gc::allocate(
                                 mc,
                                 Expr::Pap {
                                     f: *f,
-                                    args: args.to_vec(),
+                                    args: args.iter().map(|a| close(mc, *a, env)).collect(),
                                     arity: op_arity - arity,
                                 },
                             );
-                            (expr2, env)
+                            Ok((expr2, env))
                         }
                     }
                 }
                 ref _default => {
                     // rule TCALL
                     stack.write(mc).push(Cont::ApplyCont {
-                        env: env,
-                        args: args.to_vec(),
+                        env,
+                        args: args.iter().map(|a| close(mc, *a, env)).collect(),
                         arity: *arity,
                     });
-                    (*f, env)
+                    Ok((*f, env))
                 }
             }
         }
         (
-            Expr::Pap { f, args, arity },
+            Expr::Pap { f, args, .. },
             Some(Cont::ApplyCont {
                 args: ref cont_args,
-                arity: cont_arity,
+                env: cont_env,
                 ..
             }),
         ) => {
             // partial apply just mops up new arguments and returns a normal
-            // apply.
+            // apply. The pap's own args are closed, so it's fine to evaluate
+            // the new apply in the env of the new args.
             stack.write(mc).pop();
             let mut newargs = args.to_vec();
             newargs.extend(cont_args.to_vec());
@@ -157,163 +432,2433 @@ fn step<'gc>(
                 mc,
                 Expr::App {
                     f: *f,
+                    arity: newargs.len(),
                     args: newargs,
-                    arity: arity + cont_arity,
                 },
             );
-            (expr2, env)
+            Ok((expr2, cont_env))
+        }
+        (
+            Expr::PrimOp { .. } | Expr::HostFn(_),
+            Some(Cont::ApplyCont {
+                args,
+                arity,
+                env: cont_env,
+            }),
+        ) => {
+            // e.g. `map f xs` where `map` had to be looked up first.
+            stack.write(mc).pop();
+            Ok((Gc::allocate(mc, Expr::App { f: expr, args, arity }), cont_env))
+        }
+        (Expr::Lambda { .. }, Some(Cont::ApplyCont { .. })) => apply_lambda(mc, expr, env, stack),
+        (Expr::Lambda { .. }, _) => Ok((Gc::allocate(mc, Expr::Closure { lambda: expr, env }), env)),
+        (Expr::Closure { lambda, env }, Some(Cont::ApplyCont { .. })) => apply_lambda(mc, *lambda, *env, stack),
+        (e, Some(Cont::FormalsCont { lambda, env, arg })) if e.is_value() => {
+            stack.write(mc).pop();
+            bind_formals(mc, lambda, env, arg, expr)
+        }
+        (Expr::Attrs { attrs, dynamic, recursive }, _) => {
+            let bindings = attrs.iter().map(|(name, value, _)| (name.clone(), *value)).collect();
+            let (set, env) = if *recursive {
+                // the values see each other, the set itself is just a
+                // different view on the scope
+                let env = Env::new_rec(mc, env, bindings);
+                let mut set = AttrSet::new();
+                for (name, _, _) in attrs {
+                    set.insert(mc, name.clone(), env.lookup(name).unwrap());
+                }
+                (set, env)
+            } else {
+                let mut set = AttrSet::new();
+                for (name, value) in Env::close_bindings(mc, env, bindings).0 {
+                    set.insert(mc, name, value);
+                }
+                (set, env)
+            };
+            match dynamic.first() {
+                None => Ok((Gc::allocate(mc, Expr::AttrSet(set)), env)),
+                Some((key, _, _)) => {
+                    stack.write(mc).push(Cont::DynamicAttrsCont {
+                        attrs: expr,
+                        index: 0,
+                        set: GcCell::allocate(mc, set),
+                        env,
+                    });
+                    Ok((*key, env))
+                }
+            }
+        }
+        (e, Some(Cont::DynamicAttrsCont { attrs, index, set, env })) if e.is_value() => {
+            stack.write(mc).pop();
+            let dynamic = match *attrs {
+                Expr::Attrs { ref dynamic, .. } => dynamic,
+                _ => unreachable!("DynamicAttrsCont without attrs"),
+            };
+            let (_, value, pos) = dynamic[index];
+            // `{ ${null} = 1; }` is how you conditionally leave out attributes
+            if !matches!(e, Expr::Null()) {
+                let name = key_name(e)?;
+                if set.read().contains(&name) {
+                    return Err(ErrorKind::DuplicateAttribute { name, pos });
+                }
+                set.write(mc).insert(mc, name, close(mc, value, env));
+            }
+            match dynamic.get(index + 1) {
+                None => Ok((Gc::allocate(mc, Expr::AttrSet(set.read().clone())), env)),
+                Some((key, _, _)) => {
+                    stack.write(mc).push(Cont::DynamicAttrsCont {
+                        attrs,
+                        index: index + 1,
+                        set,
+                        env,
+                    });
+                    Ok((*key, env))
+                }
+            }
+        }
+        (Expr::ListLiteral(elems), _) => {
+            let elems = elems.iter().map(|e| close(mc, *e, env)).collect();
+            Ok((Gc::allocate(mc, Expr::List(elems)), env))
+        }
+        (Expr::Select { expr: set, attr_path, pos }, _) => {
+            stack.write(mc).push(Cont::SelectCont {
+                attr_path: attr_path.clone(),
+                default: None,
+                env,
+                pos: *pos,
+            });
+            Ok((*set, env))
+        }
+        (
+            Expr::SelectOr {
+                expr: set,
+                attr_path,
+                default,
+                pos,
+            },
+            _,
+        ) => {
+            stack.write(mc).push(Cont::SelectCont {
+                attr_path: attr_path.clone(),
+                default: Some(*default),
+                env,
+                pos: *pos,
+            });
+            Ok((*set, env))
+        }
+        (Expr::InheritFrom { from, name, pos }, _) => {
+            stack.write(mc).push(Cont::SelectCont {
+                attr_path: vec![Gc::allocate(mc, Expr::String(name.clone()))],
+                default: None,
+                env,
+                pos: *pos,
+            });
+            Ok((*from, env))
+        }
+        (
+            e,
+            Some(Cont::SelectCont {
+                attr_path,
+                default,
+                env,
+                pos,
+            }),
+        ) if e.is_value() => {
+            stack.write(mc).pop();
+            select(mc, expr, attr_path, default, env, pos, stack)
+        }
+        (
+            e,
+            Some(Cont::SelectKeyCont {
+                set,
+                attr_path,
+                default,
+                env,
+                pos,
+            }),
+        ) if e.is_value() => {
+            // the key's errors happen while selecting, so pop afterwards
+            let name = key_name(e)?;
+            stack.write(mc).pop();
+            select_attr(mc, set, name, &attr_path[1..], default, env, pos, stack)
+        }
+        (Expr::HasAttr { expr: set, attr_path }, _) => {
+            stack.write(mc).push(Cont::HasAttrCont {
+                attr_path: attr_path.clone(),
+                env,
+            });
+            Ok((*set, env))
+        }
+        (e, Some(Cont::HasAttrCont { attr_path, env })) if e.is_value() => {
+            stack.write(mc).pop();
+            has_attr(mc, expr, attr_path, env, stack)
+        }
+        (e, Some(Cont::HasAttrKeyCont { set, attr_path, env })) if e.is_value() => {
+            let name = key_name(e)?;
+            stack.write(mc).pop();
+            has_attr_name(mc, set, name, &attr_path[1..], env, stack)
+        }
+        (e, Some(Cont::CompareCont { name, todo, env })) if e.is_value() => {
+            stack.write(mc).pop();
+            builtins::compare_all(mc, name, todo, env, stack)
+        }
+        (e, Some(Cont::SortCont { .. })) if e.is_value() => {
+            let right_first = bool_value(e)?;
+            let cont = stack.write(mc).pop().unwrap();
+            builtins::merge(mc, cont, right_first, stack)
+        }
+        (e, Some(Cont::RetryCont { name, args })) if e.is_value() => {
+            stack.write(mc).pop();
+            apply_primop(mc, name, &args, env, stack)
+        }
+        (Expr::InterpolatedString(parts), _) => match parts.first() {
+            Some(first) => {
+                stack.write(mc).push(Cont::CoerceCont {
+                    parts: parts.clone(),
+                    next: 0,
+                    done: String::new(),
+                    env,
+                    more: false,
+                });
+                Ok((*first, env))
+            }
+            None => Ok((Gc::allocate(mc, Expr::String(String::new())), env)),
+        },
+        (
+            e,
+            Some(Cont::CoerceCont {
+                parts,
+                next,
+                done,
+                env: cont_env,
+                more,
+            }),
+        ) if e.is_value() => {
+            stack.write(mc).pop();
+            coerce(mc, expr, parts, next, done, cont_env, more, stack)
+        }
+        // `-x` is `0 - x`
+        (Expr::UnaryMinus { expr: negated }, _) => {
+            let zero = Gc::allocate(mc, Expr::Int(0));
+            Ok((crate::parser_prelude::binop(mc, "-", zero, *negated), env))
         }
         (Expr::Thunk { t }, _) => {
-            // TODO - blackholing
-            (*t, env)
-        }
-        // }
-        // Expr::Lambda { .. } => {
-        //     // check top of stack, follow call rules
-        //     unreachable!()
-        //     // (expr, env)
-        // }
-        _ => {
-            unreachable!()
-            // (expr, env)
+            let state = t.read().clone();
+            match state {
+                ThunkState::Value(value) => Ok((value, env)),
+                ThunkState::Suspended { expr, env } => {
+                    *t.write(mc) = ThunkState::Blackhole { expr, env };
+                    stack.write(mc).push(Cont::UpdateCont { t: *t });
+                    Ok((expr, env))
+                }
+                ThunkState::Blackhole { .. } => Err(ErrorKind::InfiniteRecursion { pos: None }),
+            }
         }
+        // e.g. `1 2`
+        (e, Some(Cont::ApplyCont { .. })) if e.is_value() => Err(ErrorKind::TypeError {
+            expected: "a function",
+            got: e.type_name(),
+        }),
+        (e, top) => unreachable!("no rule for {:?} with {:?} on top of the stack", e, top),
     }
 }
 
-/// eval `expr` to a value (e.g. string, float, int, lambda, ...)
-pub fn eval<'gc>(mc: MutationContext<'gc, '_>, expr: GcExpr<'gc>, max_steps: usize) -> GcExpr<'gc> {
-    let root = ExprRoot {
-        root: expr,
-        stack: GcCell::allocate(mc, Vec::new()),
-        env: Gc::allocate(mc, Env::new_root()),
-    };
-    // TODO I'm not 100% sure how pointer comparisons work after gc, so the
-    // following use of black_hole might not work at all.
-    let black_hole = Gc::allocate(mc, Expr::Null());
-    let mut s = (root.root, root.env);
-    for _i in 0..max_steps {
-        s = step(mc, s.0, s.1, black_hole, root.stack);
-        if (s.0).is_value() && root.stack.read().len() == 0 {
-            return s.0;
+/// Pair `expr` up with the `env` it has to be evaluated in, unless that's
+/// irrelevant because it's a value (or already a thunk).
+pub(crate) fn close<'gc>(mc: MutationContext<'gc, '_>, expr: GcExpr<'gc>, env: GcEnv<'gc>) -> GcExpr<'gc> {
+    match *expr {
+        _ if expr.is_closed() => expr,
+        _ => Gc::allocate(
+            mc,
+            Expr::Thunk {
+                t: GcCell::allocate(mc, ThunkState::Suspended { expr, env }),
+            },
+        ),
+    }
+}
+
+/// Look `name` up in the sets of the `with` scopes in `env`, innermost
+/// first.
+fn lookup_with<'gc>(
+    mc: MutationContext<'gc, '_>,
+    env: Option<GcEnv<'gc>>,
+    name: &str,
+    pos: Pos,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match env.and_then(Env::find_with) {
+        Some((scope, set)) => {
+            stack.write(mc).push(Cont::WithCont {
+                name: name.to_string(),
+                pos,
+                scope,
+            });
+            Ok((set, scope))
         }
+        None => Err(ErrorKind::UndefinedVariable {
+            name: name.to_string(),
+            pos,
+        }),
     }
-    unreachable!("{:?} did not evaluate in {} steps", expr, max_steps)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::expr_parser::exprParser;
-    use crate::lexer::nix_lexer::Lexer;
-    use gc_arena::rootless_arena;
+pub(crate) fn bool_value(value: &Expr) -> Result<bool, ErrorKind> {
+    match value {
+        Expr::Bool(b) => Ok(*b),
+        e => Err(ErrorKind::TypeError {
+            expected: "a Boolean",
+            got: e.type_name(),
+        }),
+    }
+}
 
-    #[test]
-    fn check_pap_primop() {
-        let mut arena = ExprArena::new(ArenaParameters::default(), |mc| ExprRoot {
-            // 2 + 1
-            // ((+ 2) 1)
-            root: Gc::allocate(
-                mc,
-                Expr::App {
-                    f: Gc::allocate(
-                        mc,
-                        Expr::App {
-                            f: Gc::allocate(mc, Expr::PrimOp { name: "+", arity: 2 }),
-                            arity: 1,
-                            args: vec![Gc::allocate(mc, Expr::Int(2))],
-                        },
-                    ),
-                    arity: 1,
-                    args: vec![Gc::allocate(mc, Expr::Int(1))],
-                },
-            ),
-            stack: GcCell::allocate(mc, Vec::new()),
-            env: Gc::allocate(mc, Env::new_root()),
-        });
-        arena.mutate(|mc, root| {
-            let black_hole = Gc::allocate(mc, Expr::Null());
-            let mut s = (root.root, root.env);
-            // TODO - need function that is essentialy `eval` that runs until no
-            // redex left.
-            for _i in 0..10 {
-                s = step(mc, s.0, s.1, black_hole, root.stack);
-                match *s.0 {
-                    Expr::Int(v) => {
-                        assert_eq!(v, 3);
-                        break;
-                    }
-                    _ => (),
-                }
-            }
-        });
+/// The name a dynamic attribute `${key}` evaluated to.
+fn key_name(key: &Expr) -> Result<String, ErrorKind> {
+    key.attr_name().ok_or(ErrorKind::TypeError {
+        expected: "a string",
+        got: key.type_name(),
+    })
+}
+
+/// Take the first attribute of `attr_path` from `set`, leaving the rest of the
+/// path on the stack if there is any. If the first attribute is dynamic we
+/// need to evaluate its name first.
+fn select<'gc>(
+    mc: MutationContext<'gc, '_>,
+    set: GcExpr<'gc>,
+    attr_path: Vec<GcExpr<'gc>>,
+    default: Option<GcExpr<'gc>>,
+    env: GcEnv<'gc>,
+    pos: Pos,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let first = attr_path[0];
+    match first.attr_name() {
+        Some(name) => select_attr(mc, set, name, &attr_path[1..], default, env, pos, stack),
+        None => {
+            stack.write(mc).push(Cont::SelectKeyCont {
+                set,
+                attr_path,
+                default,
+                env,
+                pos,
+            });
+            Ok((first, env))
+        }
     }
+}
 
-    #[test]
-    fn check_app_primop() {
-        let mut arena = ExprArena::new(ArenaParameters::default(), |mc| ExprRoot {
-            root: Gc::allocate(
-                mc,
-                Expr::App {
-                    f: Gc::allocate(mc, Expr::PrimOp { name: "+", arity: 2 }),
-                    arity: 2,
-                    args: vec![Gc::allocate(mc, Expr::Int(2)), Gc::allocate(mc, Expr::Int(1))],
-                },
-            ),
-            stack: GcCell::allocate(mc, Vec::new()),
-            env: Gc::allocate(mc, Env::new_root()),
-        });
-        arena.mutate(|mc, root| {
-            let black_hole = Gc::allocate(mc, Expr::Null());
-            let mut s = (root.root, root.env);
-            // TODO - need function that is essentialy `eval` that runs until no
-            // redex left.
-            for _i in 0..10 {
-                s = step(mc, s.0, s.1, black_hole, root.stack);
-                match *s.0 {
-                    Expr::Int(v) => {
-                        assert_eq!(v, 3);
-                        break;
-                    }
-                    _ => (),
-                }
+/// Select `name` from `set` and continue with `rest` of the path.
+#[allow(clippy::too_many_arguments)]
+fn select_attr<'gc>(
+    mc: MutationContext<'gc, '_>,
+    set: GcExpr<'gc>,
+    name: String,
+    rest: &[GcExpr<'gc>],
+    default: Option<GcExpr<'gc>>,
+    env: GcEnv<'gc>,
+    pos: Pos,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let value = match (&*set, default) {
+        (Expr::AttrSet(attrs), _) => attrs.get(&name),
+        // `or` also covers selecting from something that isn't a set
+        (_, Some(_)) => None,
+        (e, None) => {
+            return Err(ErrorKind::TypeError {
+                expected: "a set",
+                got: e.type_name(),
+            })
+        }
+    };
+    match (value, default) {
+        (Some(value), _) => {
+            if !rest.is_empty() {
+                stack.write(mc).push(Cont::SelectCont {
+                    attr_path: rest.to_vec(),
+                    default,
+                    env,
+                    pos,
+                });
             }
-        });
+            Ok((value, env))
+        }
+        (None, Some(default)) => Ok((default, env)),
+        (None, None) => Err(ErrorKind::MissingAttribute {
+            suggestions: match *set {
+                Expr::AttrSet(ref attrs) => suggestions(&name, attrs.iter().map(|(n, _)| n)),
+                _ => unreachable!(),
+            },
+            name,
+            pos: Some(pos),
+        }),
     }
+}
 
-    #[test]
-    fn check_thunk() {
-        rootless_arena(|mc| {
-            let root = ExprRoot {
-                root: Gc::allocate(
-                    mc,
-                    Expr::Thunk {
-                        t: Gc::allocate(mc, Expr::String("thunk".to_string())),
-                    },
-                ),
-                stack: GcCell::allocate(mc, Vec::new()),
-                env: Gc::allocate(mc, Env::new_root()),
-            };
-            let black_hole = Gc::allocate(mc, Expr::Null());
-            let mut s = (root.root, root.env);
-            for _i in 0..10 {
-                s = step(mc, s.0, s.1, black_hole, root.stack);
-                match *(s.0) {
-                    Expr::String(ref s) => {
-                        assert_eq!(s, "thunk");
-                        break;
-                    }
-                    _ => (),
-                }
-            }
-        });
+/// `set ? attr_path`, which is false rather than an error if anything along
+/// the path is missing or not a set.
+fn has_attr<'gc>(
+    mc: MutationContext<'gc, '_>,
+    set: GcExpr<'gc>,
+    attr_path: Vec<GcExpr<'gc>>,
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let first = attr_path[0];
+    match first.attr_name() {
+        Some(name) => has_attr_name(mc, set, name, &attr_path[1..], env, stack),
+        None => {
+            stack.write(mc).push(Cont::HasAttrKeyCont { set, attr_path, env });
+            Ok((first, env))
+        }
     }
-    #[test]
-    fn check_simple_eval() {
-        let lexer = Lexer::new("2 * 3 + 4", Vec::with_capacity(10), 0);
-        rootless_arena(|mc| {
-            let root_expr = crate::expr_parser::exprParser::new().parse(mc, lexer).unwrap();
-            let e = eval(mc, root_expr, 12);
-            println!("eval: {:?}", *e);
-        })
+}
+
+fn has_attr_name<'gc>(
+    mc: MutationContext<'gc, '_>,
+    set: GcExpr<'gc>,
+    name: String,
+    rest: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let value = match *set {
+        Expr::AttrSet(ref attrs) => attrs.get(&name),
+        _ => None,
+    };
+    match value {
+        // the last attribute itself doesn't need to be evaluated
+        Some(value) if !rest.is_empty() => {
+            stack.write(mc).push(Cont::HasAttrCont {
+                attr_path: rest.to_vec(),
+                env,
+            });
+            Ok((value, env))
+        }
+        _ => Ok((Gc::allocate(mc, Expr::Bool(value.is_some())), env)),
+    }
+}
+
+/// Names that are close to `name`, best match first, for "did you mean"
+/// hints.
+pub(crate) fn suggestions<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut close: Vec<(usize, &String)> = candidates
+        .map(|c| (levenshtein(name, c), c))
+        .filter(|&(distance, _)| distance <= 2)
+        .collect();
+    close.sort();
+    close.into_iter().take(5).map(|(_, c)| c.clone()).collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Rule for calling a lambda (defined in `env`): take the first argument off
+/// the ApplyCont and bind it. Any remaining arguments are left for whatever
+/// the body evaluates to.
+fn apply_lambda<'gc>(
+    mc: MutationContext<'gc, '_>,
+    lambda: GcExpr<'gc>,
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let top = stack.write(mc).pop();
+    let arg = match top {
+        Some(Cont::ApplyCont {
+            mut args,
+            arity,
+            env: cont_env,
+        }) => {
+            let arg = args.remove(0);
+            if !args.is_empty() {
+                stack.write(mc).push(Cont::ApplyCont {
+                    args,
+                    arity: arity - 1,
+                    env: cont_env,
+                });
+            }
+            arg
+        }
+        top => unreachable!("lambda applied without ApplyCont: {:?}", top),
+    };
+    match *lambda {
+        Expr::Lambda {
+            arg: Some(ref name),
+            formals: None,
+            body,
+            ..
+        } => {
+            let mut values = HashMap::with_capacity(1);
+            values.insert(name.clone(), arg);
+            Ok((body, Gc::allocate(mc, Env::new(Some(env), values))))
+        }
+        Expr::Lambda { formals: Some(_), .. } => {
+            // the arg is closed so it doesn't matter which env we return
+            stack.write(mc).push(Cont::FormalsCont { lambda, env, arg });
+            Ok((arg, env))
+        }
+        _ => unreachable!("not a lambda: {:?}", *lambda),
+    }
+}
+
+/// Bind the forced `value` of `arg` to the formals of `lambda`. Defaults live
+/// in the same scope as the formals, so `{ a, b ? a }: b` works.
+fn bind_formals<'gc>(
+    mc: MutationContext<'gc, '_>,
+    lambda: GcExpr<'gc>,
+    env: GcEnv<'gc>,
+    arg: GcExpr<'gc>,
+    value: GcExpr<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    let (name, formals, ellipsis, body, pos) = match *lambda {
+        Expr::Lambda {
+            arg: ref name,
+            formals: Some((ref formals, ellipsis)),
+            body,
+            pos,
+        } => (name, formals, ellipsis, body, pos),
+        _ => unreachable!("not a lambda with formals: {:?}", *lambda),
+    };
+    let set = match *value {
+        Expr::AttrSet(ref set) => set,
+        _ => {
+            return Err(ErrorKind::TypeError {
+                expected: "a set",
+                got: value.type_name(),
+            })
+        }
+    };
+    let mut bindings = Vec::with_capacity(formals.len() + 1);
+    for formal in formals {
+        match **formal {
+            Expr::Formal(ref formal_name, default) => match (set.get(formal_name), default) {
+                (Some(value), _) => bindings.push((formal_name.clone(), value)),
+                (None, Some(default)) => bindings.push((formal_name.clone(), default)),
+                (None, None) => {
+                    return Err(ErrorKind::MissingArgument {
+                        name: formal_name.clone(),
+                        pos,
+                    })
+                }
+            },
+            _ => unreachable!("not a formal: {:?}", **formal),
+        }
+    }
+    if !ellipsis {
+        for (attr, _) in set.iter() {
+            if !bindings.iter().any(|(formal_name, _)| formal_name == attr) {
+                return Err(ErrorKind::UnexpectedArgument { name: attr.clone(), pos });
+            }
+        }
+    }
+    if let Some(name) = name {
+        bindings.push((name.clone(), arg));
+    }
+    Ok((body, Env::new_rec(mc, env, bindings)))
+}
+
+fn apply_primop<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'gc str,
+    args: &[GcExpr<'gc>],
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match builtins::primop(name) {
+        Some(op) => (op.f)(mc, op.name, args, env, stack),
+        None => Err(ErrorKind::NotImplemented { name: name.to_string() }),
+    }
+}
+
+/// Whether `f` wants its `i`th argument evaluated before it's called.
+fn strict_arg(f: &Expr, i: usize) -> bool {
+    match *f {
+        Expr::PrimOp { name, .. } => builtins::primop(name).is_none_or(|op| op.is_strict(i)),
+        _ => true,
+    }
+}
+
+/// Move the arguments that don't need evaluating (any more) from the end of
+/// `unforced_args` to `forced_args` and evaluate the next one that does. Once
+/// there is none left, call `f` with them.
+fn force_args<'gc>(
+    mc: MutationContext<'gc, '_>,
+    f: GcExpr<'gc>,
+    mut unforced_args: Vec<GcExpr<'gc>>,
+    mut forced_args: Vec<GcExpr<'gc>>,
+    env: GcEnv<'gc>,
+    stack: GcStack<'gc>,
+) -> (GcExpr<'gc>, GcEnv<'gc>) {
+    while let Some(next) = unforced_args.last().cloned() {
+        if next.is_value() || !strict_arg(&f, forced_args.len()) {
+            unforced_args.pop();
+            forced_args.push(next);
+            continue;
+        }
+        stack.write(mc).push(Cont::ForceAppCont {
+            f,
+            unforced_args,
+            forced_args,
+            env,
+        });
+        return (next, env);
+    }
+    let call = Expr::App {
+        f,
+        arity: forced_args.len(),
+        args: forced_args,
+    };
+    (Gc::allocate(mc, call), env)
+}
+
+/// Append `value`, which is `parts[next]`, to `done` and carry on with the
+/// next part. Sets and lists (with `more`) need evaluating more first.
+#[allow(clippy::too_many_arguments)]
+fn coerce<'gc>(
+    mc: MutationContext<'gc, '_>,
+    value: GcExpr<'gc>,
+    mut parts: Vec<GcExpr<'gc>>,
+    mut next: usize,
+    mut done: String,
+    env: GcEnv<'gc>,
+    more: bool,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    match *value {
+        Expr::String(ref s) => done.push_str(s),
+        Expr::Path(ref p) if more => done.push_str(p),
+        Expr::Path(ref p) => done.push_str(&copy_to_store(p)?),
+        Expr::AttrSet(ref set) => {
+            // the set's string is whatever `__toString` or `outPath` coerce to
+            let next_value = match (set.get("__toString"), set.get("outPath")) {
+                (Some(f), _) => Gc::allocate(
+                    mc,
+                    Expr::App {
+                        f,
+                        args: vec![value],
+                        arity: 1,
+                    },
+                ),
+                (None, Some(out_path)) => out_path,
+                (None, None) => return Err(ErrorKind::CannotCoerce { got: value.type_name() }),
+            };
+            stack.write(mc).push(Cont::CoerceCont {
+                parts,
+                next,
+                done,
+                env,
+                more,
+            });
+            return Ok((next_value, env));
+        }
+        Expr::List(ref elems) if more => {
+            // `toString [ 1 [ 2 3 ] ]` is "1 2 3", but nothing gets added
+            // for empty lists. TODO we only see that an element is an
+            // empty list if it's been evaluated already.
+            let mut spliced = Vec::with_capacity(2 * elems.len());
+            for (i, elem) in elems.iter().enumerate() {
+                spliced.push(*elem);
+                let empty = matches!(peek(*elem).as_deref(), Some(Expr::List(l)) if l.is_empty());
+                if i + 1 < elems.len() && !empty {
+                    spliced.push(Gc::allocate(mc, Expr::String(" ".to_string())));
+                }
+            }
+            parts.splice(next..=next, spliced);
+            return coerce_next(mc, parts, next, done, env, more, stack);
+        }
+        Expr::Null() if more => {}
+        Expr::Bool(b) if more => done.push_str(if b { "1" } else { "" }),
+        Expr::Int(i) if more => done.push_str(&i.to_string()),
+        Expr::Float(x) if more => done.push_str(&float_to_string(x)),
+        ref e => return Err(ErrorKind::CannotCoerce { got: e.type_name() }),
+    }
+    next += 1;
+    coerce_next(mc, parts, next, done, env, more, stack)
+}
+
+/// Evaluate `parts[next]`, or return the string if we're done.
+fn coerce_next<'gc>(
+    mc: MutationContext<'gc, '_>,
+    parts: Vec<GcExpr<'gc>>,
+    next: usize,
+    done: String,
+    env: GcEnv<'gc>,
+    more: bool,
+    stack: GcStack<'gc>,
+) -> Result<(GcExpr<'gc>, GcEnv<'gc>), ErrorKind> {
+    if next == parts.len() {
+        return Ok((Gc::allocate(mc, Expr::String(done)), env));
+    }
+    let part = parts[next];
+    stack.write(mc).push(Cont::CoerceCont {
+        parts,
+        next,
+        done,
+        env,
+        more,
+    });
+    Ok((part, env))
+}
+
+/// There's no store yet, so this doesn't copy anything. It only works out
+/// where `path` would go from a sha256 of its contents, see `hash_nar`, and
+/// its name, following nix's recipe for store paths of sources.
+// TODO relative paths should be resolved against the directory of the file
+// they're in, but we don't know that yet, see `canonical_path`.
+fn copy_to_store(path: &str) -> Result<String, ErrorKind> {
+    use sha2::{Digest, Sha256};
+
+    let error = |error: String| ErrorKind::CannotCopyToStore {
+        path: path.to_string(),
+        error,
+    };
+    if !path.starts_with('/') {
+        return Err(error("relative paths aren't supported yet".to_string()));
+    }
+    let mut nar = Sha256::new();
+    hash_nar(&mut nar, std::path::Path::new(path)).map_err(|e| error(e.to_string()))?;
+    let nar_hash: String = nar.result().iter().map(|byte| format!("{:02x}", byte)).collect();
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let fingerprint = format!("source:sha256:{}:/nix/store:{}", nar_hash, name);
+    // nix shortens hashes in store paths to 160 bits by xor-ing the rest in
+    let mut hash = [0; 20];
+    for (i, byte) in Sha256::digest(fingerprint.as_bytes()).iter().enumerate() {
+        hash[i % hash.len()] ^= byte;
+    }
+    Ok(format!("/nix/store/{}-{}", nix_base32(&hash), name))
+}
+
+/// Feed `path` to `hash` the way nix writes it into an archive (a NAR),
+/// which only keeps what's in files, whether they're executable, and where
+/// symlinks point.
+fn hash_nar(hash: &mut sha2::Sha256, path: &std::path::Path) -> std::io::Result<()> {
+    nar_string(hash, b"nix-archive-1");
+    hash_nar_node(hash, path)
+}
+
+fn hash_nar_node(hash: &mut sha2::Sha256, path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    nar_string(hash, b"(");
+    nar_string(hash, b"type");
+    if metadata.file_type().is_symlink() {
+        nar_string(hash, b"symlink");
+        nar_string(hash, b"target");
+        nar_string(hash, std::fs::read_link(path)?.as_os_str().as_bytes());
+    } else if metadata.is_dir() {
+        nar_string(hash, b"directory");
+        let mut names = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort();
+        for name in names {
+            nar_string(hash, b"entry");
+            nar_string(hash, b"(");
+            nar_string(hash, b"name");
+            nar_string(hash, name.as_bytes());
+            nar_string(hash, b"node");
+            hash_nar_node(hash, &path.join(&name))?;
+            nar_string(hash, b")");
+        }
+    } else {
+        nar_string(hash, b"regular");
+        if metadata.permissions().mode() & 0o100 != 0 {
+            nar_string(hash, b"executable");
+            nar_string(hash, b"");
+        }
+        nar_string(hash, b"contents");
+        nar_string(hash, &std::fs::read(path)?);
+    }
+    nar_string(hash, b")");
+    Ok(())
+}
+
+/// Strings in NARs are their length and then the bytes, padded with zeros
+/// to a multiple of 8.
+fn nar_string(hash: &mut sha2::Sha256, s: &[u8]) {
+    use sha2::Digest;
+
+    hash.input((s.len() as u64).to_le_bytes());
+    hash.input(s);
+    hash.input(&[0; 8][..(8 - s.len() % 8) % 8]);
+}
+
+/// The base 32 nix uses for hashes, which leaves out e, o, u and t and
+/// starts with the last bits of `bytes`.
+fn nix_base32(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    let len = (bytes.len() * 8).div_ceil(5);
+    (0..len)
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let low = u16::from(bytes[i]) >> j;
+            let high = bytes.get(i + 1).map_or(0, |&b| u16::from(b) << (8 - j));
+            DIGITS[usize::from((low | high) & 0x1f)] as char
+        })
+        .collect()
+}
+
+/// Get rid of `.`, `..` and duplicate slashes in absolute paths, like nix
+/// does after `/a + "/../b"`.
+// TODO relative paths should have been made absolute by the parser
+pub(crate) fn canonical_path(path: &str) -> String {
+    if !path.starts_with('/') {
+        return path.to_string();
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            c => components.push(c),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+/// Primops only get their arguments evaluated, not what's inside of them.
+/// Ones that need to look deeper, e.g. comparing lists, ask for the
+/// thunks they run into to be forced and then start over, see
+/// `Cont::RetryCont`.
+pub(crate) enum Need<'gc, T> {
+    Value(T),
+    Force(GcExpr<'gc>),
+}
+
+/// The value behind `expr`, or `None` if it's a thunk nobody forced yet.
+pub(crate) fn peek<'gc>(expr: GcExpr<'gc>) -> Option<GcExpr<'gc>> {
+    match *expr {
+        Expr::Thunk { t } => match *t.read() {
+            ThunkState::Value(value) => Some(value),
+            ThunkState::Suspended { .. } | ThunkState::Blackhole { .. } => None,
+        },
+        _ => Some(expr),
+    }
+}
+
+/// Look at what's behind `expr`, or return from the surrounding function
+/// asking for it to be forced.
+macro_rules! peek {
+    ($expr:expr) => {
+        match peek($expr) {
+            Some(value) => value,
+            None => return Ok(Need::Force($expr)),
+        }
+    };
+}
+
+#[derive(Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+fn number(expr: &Expr) -> Option<Number> {
+    match expr {
+        Expr::Int(i) => Some(Number::Int(*i)),
+        Expr::Float(f) => Some(Number::Float(*f)),
+        _ => None,
+    }
+}
+
+/// `+`, `-`, `*` and `/`. Integers stay integers (and overflowing is an
+/// error), as soon as a float is involved the result is a float.
+pub(crate) fn arithmetic<'gc>(op: &str, left: &Expr<'gc>, right: &Expr<'gc>) -> Result<Expr<'gc>, ErrorKind> {
+    let (l, r) = match (number(left), number(right)) {
+        (Some(l), Some(r)) => (l, r),
+        (None, _) => return Err(number_expected(left)),
+        (_, None) => return Err(number_expected(right)),
+    };
+    match (l, r) {
+        (Number::Int(l), Number::Int(r)) => {
+            let result = match op {
+                "+" => l.checked_add(r),
+                "-" => l.checked_sub(r),
+                "*" => l.checked_mul(r),
+                _ if r == 0 => return Err(ErrorKind::DivisionByZero),
+                // truncates, i.e. -7 / 2 == -3
+                _ => l.checked_div(r),
+            };
+            result.map(Expr::Int).ok_or(ErrorKind::IntegerOverflow {
+                op: op.to_string(),
+                left: l,
+                right: r,
+            })
+        }
+        (l, r) => {
+            let (l, r) = (float(l), float(r));
+            let result = match op {
+                "+" => l + r,
+                "-" => l - r,
+                "*" => l * r,
+                _ if r == 0.0 => return Err(ErrorKind::DivisionByZero),
+                _ => l / r,
+            };
+            Ok(Expr::Float(result))
+        }
+    }
+}
+
+fn float(n: Number) -> f64 {
+    match n {
+        Number::Int(i) => i as f64,
+        Number::Float(f) => f,
+    }
+}
+
+fn number_expected(value: &Expr) -> ErrorKind {
+    ErrorKind::TypeError {
+        expected: "an integer",
+        got: value.type_name(),
+    }
+}
+
+/// Carry on with the comparisons in `todo` until we know the answer, or
+/// need a thunk inside of a value forced. In that case `todo` is left so
+/// that calling this again, once it's forced, carries on where it stopped.
+///
+/// Numbers, strings and paths compare the obvious way, lists and sets
+/// element by element, in order, so a difference is found without forcing
+/// what comes after it.
+pub(crate) fn compare_values<'gc>(
+    mc: MutationContext<'gc, '_>,
+    todo: &mut Vec<Comparison<'gc>>,
+) -> Result<Need<'gc, bool>, ErrorKind> {
+    // what the last finished comparison came out as, for the one below it
+    let mut result = None;
+    while let Some(comparison) = todo.pop() {
+        result = match comparison {
+            Comparison::Equal(l, r) => match (peek(l), peek(r)) {
+                (Some(x), Some(y)) => match start_equal(mc, x, y, todo)? {
+                    Need::Value(result) => result,
+                    Need::Force(thunk) => {
+                        todo.push(Comparison::Equal(l, r));
+                        return Ok(Need::Force(thunk));
+                    }
+                },
+                (x, _) => {
+                    todo.push(Comparison::Equal(l, r));
+                    return Ok(Need::Force(if x.is_none() { l } else { r }));
+                }
+            },
+            Comparison::LessThan(l, r) => match (peek(l), peek(r)) {
+                (Some(x), Some(y)) => start_less_than(x, y, todo)?,
+                (x, _) => {
+                    todo.push(Comparison::LessThan(l, r));
+                    return Ok(Need::Force(if x.is_none() { l } else { r }));
+                }
+            },
+            // `result` is `None` when starting, otherwise it's about `next`
+            Comparison::AllEqual { left, right, mut next } => match result {
+                Some(false) => Some(false),
+                _ => {
+                    next += result.map_or(0, |_| 1);
+                    if next == elems(&left).len() {
+                        Some(true)
+                    } else {
+                        let pair = Comparison::Equal(elems(&left)[next], elems(&right)[next]);
+                        todo.push(Comparison::AllEqual { left, right, next });
+                        todo.push(pair);
+                        None
+                    }
+                }
+            },
+            Comparison::Lexicographic { left, right, mut next } => match result {
+                Some(false) => {
+                    todo.push(Comparison::LessThan(elems(&left)[next], elems(&right)[next]));
+                    None
+                }
+                _ => {
+                    next += result.map_or(0, |_| 1);
+                    if next == elems(&right).len() {
+                        Some(false)
+                    } else if next == elems(&left).len() {
+                        Some(true)
+                    } else {
+                        let pair = Comparison::Equal(elems(&left)[next], elems(&right)[next]);
+                        todo.push(Comparison::Lexicographic { left, right, next });
+                        todo.push(pair);
+                        None
+                    }
+                }
+            },
+            Comparison::Elem { x, list, mut next } => match result {
+                Some(true) => Some(true),
+                _ => {
+                    next += result.map_or(0, |_| 1);
+                    if next == elems(&list).len() {
+                        Some(false)
+                    } else {
+                        let pair = Comparison::Equal(x, elems(&list)[next]);
+                        todo.push(Comparison::Elem { x, list, next });
+                        todo.push(pair);
+                        None
+                    }
+                }
+            },
+        };
+    }
+    Ok(Need::Value(result.expect("comparison without a result")))
+}
+
+fn elems<'a, 'gc>(list: &'a GcExpr<'gc>) -> &'a [GcExpr<'gc>] {
+    match **list {
+        Expr::List(ref elems) => elems,
+        _ => unreachable!("comparing elements of something that isn't a list"),
+    }
+}
+
+/// Whether `set` has `type = "derivation"`, see `peek!`.
+macro_rules! is_derivation {
+    ($set:expr) => {
+        match $set.get("type") {
+            Some(t) => matches!(*peek!(t), Expr::String(ref s) if s == "derivation"),
+            None => false,
+        }
+    };
+}
+
+/// `left == right`, or `None` if that depends on what's inside of them, in
+/// which case the comparisons for that are pushed onto `todo`.
+fn start_equal<'gc>(
+    mc: MutationContext<'gc, '_>,
+    left: GcExpr<'gc>,
+    right: GcExpr<'gc>,
+    todo: &mut Vec<Comparison<'gc>>,
+) -> Result<Need<'gc, Option<bool>>, ErrorKind> {
+    if let (Some(l), Some(r)) = (number(&left), number(&right)) {
+        return Ok(Need::Value(Some(match (l, r) {
+            (Number::Int(l), Number::Int(r)) => l == r,
+            (l, r) => float(l) == float(r),
+        })));
+    }
+    let equal = match (&*left, &*right) {
+        (Expr::Null(), Expr::Null()) => true,
+        (Expr::Bool(l), Expr::Bool(r)) => l == r,
+        // TODO strings will have to ignore their context once they get one
+        (Expr::String(l), Expr::String(r)) | (Expr::Path(l), Expr::Path(r)) => l == r,
+        (Expr::List(l), Expr::List(r)) => {
+            if l.len() != r.len() {
+                return Ok(Need::Value(Some(false)));
+            }
+            todo.push(Comparison::AllEqual { left, right, next: 0 });
+            return Ok(Need::Value(None));
+        }
+        (Expr::AttrSet(l), Expr::AttrSet(r)) => {
+            if Gc::ptr_eq(left, right) {
+                return Ok(Need::Value(Some(true)));
+            }
+            // derivations are the same if they build the same thing
+            if is_derivation!(l) && is_derivation!(r) {
+                if let (Some(x), Some(y)) = (l.get("outPath"), r.get("outPath")) {
+                    todo.push(Comparison::Equal(x, y));
+                    return Ok(Need::Value(None));
+                }
+            }
+            if l.len() != r.len() || l.iter().zip(r.iter()).any(|((kx, _), (ky, _))| kx != ky) {
+                return Ok(Need::Value(Some(false)));
+            }
+            todo.push(Comparison::AllEqual {
+                left: Gc::allocate(mc, Expr::List(l.iter().map(|(_, x)| *x).collect())),
+                right: Gc::allocate(mc, Expr::List(r.iter().map(|(_, y)| *y).collect())),
+                next: 0,
+            });
+            return Ok(Need::Value(None));
+        }
+        // functions are never equal, and neither are values of different types
+        _ => false,
+    };
+    Ok(Need::Value(Some(equal)))
+}
+
+/// `left < right`, or `None` for lists, see `start_equal`.
+fn start_less_than<'gc>(
+    left: GcExpr<'gc>,
+    right: GcExpr<'gc>,
+    todo: &mut Vec<Comparison<'gc>>,
+) -> Result<Option<bool>, ErrorKind> {
+    match (number(&left), number(&right)) {
+        (Some(Number::Int(l)), Some(Number::Int(r))) => return Ok(Some(l < r)),
+        (Some(l), Some(r)) => return Ok(Some(float(l) < float(r))),
+        _ => {}
+    }
+    match (&*left, &*right) {
+        (Expr::String(l), Expr::String(r)) | (Expr::Path(l), Expr::Path(r)) => Ok(Some(l < r)),
+        (Expr::List(_), Expr::List(_)) => {
+            todo.push(Comparison::Lexicographic { left, right, next: 0 });
+            Ok(None)
+        }
+        (l, r) => Err(ErrorKind::CannotCompare {
+            left: l.type_name(),
+            right: r.type_name(),
+        }),
+    }
+}
+
+/// eval `expr` to a value (e.g. string, float, int, lambda, ...)
+///
+/// This runs in the `MutationContext` it's given, so nothing is garbage
+/// collected before it returns. Use `Evaluation` for anything big.
+///
+/// Only absolute paths can be interpolated into strings, e.g. `"${/a/b}"`.
+/// `"${./b}"` fails, since `expr` doesn't say which file it's from.
+pub fn eval<'gc>(
+    mc: MutationContext<'gc, '_>,
+    expr: GcExpr<'gc>,
+    max_steps: usize,
+) -> Result<GcExpr<'gc>, EvalError> {
+    eval_in(mc, expr, builtins::root_env(mc), max_steps)
+}
+
+/// Like `eval`, but in `env`, e.g. one from `builtins::root_env_with`.
+pub fn eval_in<'gc>(
+    mc: MutationContext<'gc, '_>,
+    expr: GcExpr<'gc>,
+    env: GcEnv<'gc>,
+    max_steps: usize,
+) -> Result<GcExpr<'gc>, EvalError> {
+    let root = ExprRoot::new(mc, expr, env);
+    match run(mc, &root, max_steps, None)? {
+        Some(value) => Ok(value),
+        None => {
+            let error = EvalError::new(ErrorKind::StepLimit { max_steps }, &root.stack.read());
+            Err(error)
+        }
+    }
+}
+
+/// Call the nix function `f`, e.g. what `eval` returned, with `args`. It can
+/// be called again after an error, values it shares with earlier calls are
+/// left as they were before the failed one.
+pub fn call<'gc>(
+    mc: MutationContext<'gc, '_>,
+    f: GcExpr<'gc>,
+    args: &[Value],
+    max_steps: usize,
+) -> Result<GcExpr<'gc>, EvalError> {
+    eval(mc, application(mc, f, args), max_steps)
+}
+
+fn application<'gc>(mc: MutationContext<'gc, '_>, f: GcExpr<'gc>, args: &[Value]) -> GcExpr<'gc> {
+    let call = Expr::App {
+        f,
+        args: args.iter().map(|arg| arg.to_expr(mc)).collect(),
+        arity: args.len(),
+    };
+    Gc::allocate(mc, call)
+}
+
+/// Take up to `steps` steps from wherever `root` is at and remember where we
+/// got to. Returns the value once there is nothing left to do. Setting
+/// `interrupt` stops before the next step with `ErrorKind::Interrupted`.
+fn run<'gc>(
+    mc: MutationContext<'gc, '_>,
+    root: &ExprRoot<'gc>,
+    steps: usize,
+    interrupt: Option<&AtomicBool>,
+) -> Result<Option<GcExpr<'gc>>, EvalError> {
+    let mut s = (*root.root.read(), *root.env.read());
+    let mut taken = 0;
+    let result = loop {
+        if s.0.is_value() && root.stack.read().is_empty() {
+            break Ok(Some(s.0));
+        }
+        if taken == steps {
+            break Ok(None);
+        }
+        if interrupt.is_some_and(|i| i.load(AtomicOrdering::Relaxed)) {
+            break Err(EvalError::new(ErrorKind::Interrupted, &root.stack.read()));
+        }
+        match step(mc, s.0, s.1, root.stack) {
+            Ok(next) => s = next,
+            Err(kind) => {
+                let error = EvalError::new(kind, &root.stack.read());
+                unwind(mc, &root.stack.read());
+                break Err(error);
+            }
+        }
+        taken += 1;
+    };
+    *root.root.write(mc) = s.0;
+    *root.env.write(mc) = s.1;
+    result
+}
+
+/// An error gives up on everything on `stack`. The thunks that were being
+/// forced go back to how they were before, like in nix, so forcing one of
+/// them again (e.g. in another `call`) runs into the same error instead of
+/// infinite recursion.
+fn unwind<'gc>(mc: MutationContext<'gc, '_>, stack: &[Cont<'gc>]) {
+    for cont in stack {
+        if let Cont::UpdateCont { t } = cont {
+            let state = t.read().clone();
+            if let ThunkState::Blackhole { expr, env } = state {
+                *t.write(mc) = ThunkState::Suspended { expr, env };
+            }
+        }
+    }
+}
+
+/// Evaluates an expression a slice of steps at a time and collects garbage
+/// in between, so memory use doesn't grow with the number of steps taken.
+/// It can stop after any number of steps and carry on later, see `resume`.
+pub struct Evaluation {
+    arena: ExprArena,
+    /// How many steps to take before letting the garbage collector pay off
+    /// its debt. 0 is taken as 1.
+    pub steps_per_slice: usize,
+    /// Checked before every step. Setting it (e.g. from a Ctrl-C handler)
+    /// makes `resume` return `Progress::Interrupted`, and it's up to the
+    /// caller to reset it before resuming.
+    pub interrupt: Arc<AtomicBool>,
+}
+
+/// How far `Evaluation::resume` got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// There's a value, see `Evaluation::value`.
+    Done,
+    /// Used up all the steps it was given.
+    OutOfFuel,
+    Interrupted,
+}
+
+impl Evaluation {
+    /// `f` builds the expression to evaluate, e.g. by parsing it. See `eval`
+    /// for which paths it can interpolate.
+    pub fn new(f: impl for<'gc> FnOnce(MutationContext<'gc, '_>) -> GcExpr<'gc>) -> Evaluation {
+        Evaluation::with_host(&Host::new(), f)
+    }
+
+    /// Like `new`, with what `host` provides in scope.
+    pub fn with_host(host: &Host, f: impl for<'gc> FnOnce(MutationContext<'gc, '_>) -> GcExpr<'gc>) -> Evaluation {
+        let arena = ExprArena::new(ArenaParameters::default(), |mc| {
+            let expr = f(mc);
+            ExprRoot::new(mc, expr, builtins::root_env_with(mc, host))
+        });
+        Evaluation {
+            arena,
+            steps_per_slice: 1000,
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Carry on evaluating for at most `fuel` steps. Unless there was an
+    /// error, calling it again picks up where this call stopped.
+    pub fn resume(&mut self, fuel: usize) -> Result<Progress, EvalError> {
+        let mut fuel = fuel;
+        loop {
+            let slice = self.steps_per_slice.max(1).min(fuel);
+            let interrupt = &self.interrupt;
+            let result = self.arena.mutate(|mc, root| run(mc, root, slice, Some(interrupt)).map(|v| v.is_some()));
+            match result {
+                Ok(true) => return Ok(Progress::Done),
+                Ok(false) if fuel == slice => return Ok(Progress::OutOfFuel),
+                Ok(false) => fuel -= slice,
+                Err(EvalError {
+                    kind: ErrorKind::Interrupted,
+                    ..
+                }) => return Ok(Progress::Interrupted),
+                Err(error) => return Err(error),
+            }
+            self.arena.collect_debt();
+        }
+    }
+
+    /// Hand the value to `f` once `resume` is `Done`. Values can't leave the
+    /// arena, so `f` has to turn it into something that can.
+    pub fn value<R>(&mut self, f: impl for<'gc> FnOnce(MutationContext<'gc, '_>, GcExpr<'gc>) -> R) -> Option<R> {
+        self.arena.mutate(|mc, root| {
+            let value = *root.root.read();
+            if value.is_value() && root.stack.read().is_empty() {
+                Some(f(mc, value))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Once `resume` is `Done`, call the value, which has to be a function,
+    /// with `args`. Resuming evaluates the call. Returns `false` if there is
+    /// no value yet.
+    pub fn call(&mut self, args: &[Value]) -> bool {
+        self.arena.mutate(|mc, root| {
+            let value = *root.root.read();
+            let done = value.is_value() && root.stack.read().is_empty();
+            if done {
+                *root.root.write(mc) = application(mc, value, args);
+            }
+            done
+        })
+    }
+
+    /// Evaluate for at most `max_steps` steps and hand the result to `f`.
+    /// Running out of steps or being interrupted are errors here.
+    pub fn run<R>(
+        &mut self,
+        max_steps: usize,
+        f: impl for<'gc> FnOnce(MutationContext<'gc, '_>, Result<GcExpr<'gc>, EvalError>) -> R,
+    ) -> R {
+        let kind = match self.resume(max_steps) {
+            Ok(Progress::Done) => return self.arena.mutate(|mc, root| f(mc, Ok(*root.root.read()))),
+            Ok(Progress::OutOfFuel) => ErrorKind::StepLimit { max_steps },
+            Ok(Progress::Interrupted) => ErrorKind::Interrupted,
+            Err(error) => return self.arena.mutate(|mc, _| f(mc, Err(error))),
+        };
+        self.arena.mutate(|mc, root| {
+            let error = EvalError::new(kind, &root.stack.read());
+            f(mc, Err(error))
+        })
+    }
+
+    /// How many bytes the arena holds on to right now.
+    pub fn total_allocated(&self) -> usize {
+        self.arena.total_allocated()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::expr::ExprArena;
+    use crate::expr_parser::exprParser;
+    use crate::lexer::nix_lexer::Lexer;
+    use gc_arena::{rootless_arena, ArenaParameters};
+    use sha2::Digest;
+
+    #[test]
+    fn check_pap_primop() {
+        let mut arena = ExprArena::new(ArenaParameters::default(), |mc| {
+            // 2 + 1
+            // ((+ 2) 1)
+            let expr = Gc::allocate(
+                mc,
+                Expr::App {
+                    f: Gc::allocate(
+                        mc,
+                        Expr::App {
+                            f: Gc::allocate(mc, Expr::PrimOp { name: "+", arity: 2 }),
+                            arity: 1,
+                            args: vec![Gc::allocate(mc, Expr::Int(2))],
+                        },
+                    ),
+                    arity: 1,
+                    args: vec![Gc::allocate(mc, Expr::Int(1))],
+                },
+            );
+            ExprRoot::new(mc, expr, Gc::allocate(mc, Env::new_root()))
+        });
+        arena.mutate(|mc, root| {
+            let mut s = (*root.root.read(), *root.env.read());
+            // TODO - need function that is essentialy `eval` that runs until no
+            // redex left.
+            for _i in 0..10 {
+                s = step(mc, s.0, s.1, root.stack).unwrap();
+                if let Expr::Int(v) = *s.0 {
+                    assert_eq!(v, 3);
+                    break;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn check_app_primop() {
+        let mut arena = ExprArena::new(ArenaParameters::default(), |mc| {
+            let expr = Gc::allocate(
+                mc,
+                Expr::App {
+                    f: Gc::allocate(mc, Expr::PrimOp { name: "+", arity: 2 }),
+                    arity: 2,
+                    args: vec![Gc::allocate(mc, Expr::Int(2)), Gc::allocate(mc, Expr::Int(1))],
+                },
+            );
+            ExprRoot::new(mc, expr, Gc::allocate(mc, Env::new_root()))
+        });
+        arena.mutate(|mc, root| {
+            let mut s = (*root.root.read(), *root.env.read());
+            // TODO - need function that is essentialy `eval` that runs until no
+            // redex left.
+            for _i in 0..10 {
+                s = step(mc, s.0, s.1, root.stack).unwrap();
+                if let Expr::Int(v) = *s.0 {
+                    assert_eq!(v, 3);
+                    break;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn check_collecting_garbage() {
+        let evaluation = |src: &str| {
+            let mut evaluation =
+                Evaluation::new(|mc| exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap());
+            evaluation.steps_per_slice = 100;
+            evaluation
+        };
+        let count_down = |n: usize| {
+            let mut evaluation = evaluation(&format!("let f = n: if n == 0 then 0 else f (n - 1); in f {}", n));
+            let result = evaluation.run(10_000_000, |_, result| result.map(|value| value.to_string()));
+            assert_eq!(result, Ok("0".to_string()));
+            evaluation.total_allocated()
+        };
+        // everything an iteration allocates is garbage after the next one
+        let (small, large) = (count_down(1_000), count_down(100_000));
+        assert!(large < 2 * small, "{} bytes after 100000 iterations, {} after 1000", large, small);
+
+        assert!(matches!(
+            evaluation("let x = x; in x").run(100, |_, result| result.map(|_| ()).map_err(|e| e.kind)),
+            Err(ErrorKind::InfiniteRecursion { .. })
+        ));
+        assert_eq!(
+            evaluation("let f = x: f x; in f 1").run(250, |_, result| result.map(|_| ()).map_err(|e| e.kind)),
+            Err(ErrorKind::StepLimit { max_steps: 250 })
+        );
+    }
+
+    #[test]
+    fn check_resuming() {
+        let src = "let f = n: if n == 0 then 42 else f (n - 1); in f 1000";
+        let mut evaluation =
+            Evaluation::new(|mc| exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap());
+        assert_eq!(evaluation.value(|_, value| value.to_string()), None);
+        let mut slices = 1;
+        while evaluation.resume(500) == Ok(Progress::OutOfFuel) {
+            slices += 1;
+        }
+        assert!(slices > 1);
+        assert_eq!(evaluation.value(|_, value| value.to_string()), Some("42".to_string()));
+
+        let mut evaluation =
+            Evaluation::new(|mc| exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap());
+        evaluation.steps_per_slice = 0;
+        assert_eq!(evaluation.resume(100), Ok(Progress::OutOfFuel));
+        assert_eq!(evaluation.resume(1_000_000), Ok(Progress::Done));
+
+        let mut evaluation =
+            Evaluation::new(|mc| exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap());
+        assert_eq!(evaluation.resume(100), Ok(Progress::OutOfFuel));
+        evaluation.interrupt.store(true, AtomicOrdering::Relaxed);
+        assert_eq!(evaluation.resume(100), Ok(Progress::Interrupted));
+        assert_eq!(
+            evaluation.run(100, |_, result| result.map(|_| ()).map_err(|e| e.kind)),
+            Err(ErrorKind::Interrupted)
+        );
+        evaluation.interrupt.store(false, AtomicOrdering::Relaxed);
+        assert_eq!(evaluation.run(1_000_000, |_, result| result.map(|value| value.to_string())), Ok("42".to_string()));
+    }
+
+    #[test]
+    fn check_failed_thunk() {
+        // `b` and the `a` it's forcing both fail, neither may stay blackholed
+        for src in &["let t = throw \"boom\"; in _: t", "let a = throw \"boom\"; b = a + 1; in _: b"] {
+            rootless_arena(|mc| {
+                let f = eval(mc, exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap(), 100).unwrap();
+                for _ in 0..2 {
+                    assert_eq!(
+                        call(mc, f, &[Value::Null], 100).map_err(|e| e.kind).map(|_| ()),
+                        Err(ErrorKind::Throw {
+                            message: "boom".to_string()
+                        }),
+                        "{}",
+                        src
+                    );
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn check_thunk() {
+        rootless_arena(|mc| {
+            let env = Gc::allocate(mc, Env::new_root());
+            let t = GcCell::allocate(
+                mc,
+                ThunkState::Suspended {
+                    expr: Gc::allocate(mc, Expr::String("thunk".to_string())),
+                    env,
+                },
+            );
+            let root = ExprRoot::new(mc, Gc::allocate(mc, Expr::Thunk { t }), env);
+            let mut s = (*root.root.read(), *root.env.read());
+            for _i in 0..10 {
+                s = step(mc, s.0, s.1, root.stack).unwrap();
+                if let Expr::String(ref s) = *(s.0) {
+                    assert_eq!(s, "thunk");
+                    break;
+                }
+            }
+            // the string is already a value so the update happens in the
+            // step after we saw it.
+            step(mc, s.0, s.1, root.stack).unwrap();
+            let state = t.read().clone();
+            match state {
+                ThunkState::Value(v) => assert!(matches!(*v, Expr::String(_))),
+                state => panic!("thunk was not updated: {:?}", state),
+            }
+        });
+    }
+    #[test]
+    fn check_blackhole() {
+        assert_eq!(
+            eval_int(include_str!("lang-tests/eval-fail-blackhole.nix")),
+            Err(ErrorKind::InfiniteRecursion { pos: Some(Pos { line: 4 }) })
+        );
+        assert_eq!(
+            eval_int("let x = x; in x"),
+            Err(ErrorKind::InfiniteRecursion { pos: Some(Pos { line: 1 }) })
+        );
+        assert_eq!(
+            eval_int("let x = 1 + x; in x").unwrap_err().to_string(),
+            "infinite recursion encountered at line 1"
+        );
+        assert!(matches!(
+            eval_int("rec { a = b; b = a; }.a"),
+            Err(ErrorKind::InfiniteRecursion { .. })
+        ));
+        // depending on an evaluated thunk is fine
+        assert_eq!(eval_int("let x = { a = 1; b = x.a; }; in x.b"), Ok(1));
+        assert_eq!(eval_int("let x = 2; y = x * x; in y + y"), Ok(8));
+    }
+
+    #[test]
+    fn check_simple_eval() {
+        let lexer = Lexer::new("2 * 3 + 4", Vec::with_capacity(10), 0);
+        rootless_arena(|mc| {
+            let root_expr = crate::expr_parser::exprParser::new().parse(mc, lexer).unwrap();
+            let e = eval(mc, root_expr, 12).unwrap();
+            println!("eval: {:?}", *e);
+        })
+    }
+
+    #[test]
+    fn check_let() {
+        for &(src, expected) in &[
+            ("let x = 1; in x", 1),
+            ("let a = b; b = 10; in a", 10),
+            ("let b = 10; a = b; in a", 10),
+            ("let a = 1; in let b = a + 1; in b * b", 4),
+            ("let x = 2; y = x * x; in y + y", 8),
+            ("let x = 1; in let x = 2; in x", 2),
+        ] {
+            let lexer = Lexer::new(src, Vec::with_capacity(10), 0);
+            rootless_arena(|mc| {
+                let root_expr = exprParser::new().parse(mc, lexer).unwrap();
+                match *eval(mc, root_expr, 100).unwrap() {
+                    Expr::Int(v) => assert_eq!(v, expected, "{}", src),
+                    ref e => panic!("{} evaluated to {:?}", src, e),
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn check_var_lookup() {
+        rootless_arena(|mc| {
+            let parse = |src| exprParser::new().parse(mc, Lexer::new(src, Vec::with_capacity(10), 0)).unwrap();
+            assert!(matches!(*eval(mc, parse("true"), 10).unwrap(), Expr::Bool(true)));
+            assert!(matches!(*eval(mc, parse("null"), 10).unwrap(), Expr::Null()));
+            assert!(matches!(*eval(mc, parse("map"), 10).unwrap(), Expr::PrimOp { name: "map", .. }));
+            assert!(matches!(*eval(mc, parse("let a = 1; in let b = a; in b"), 20).unwrap(), Expr::Int(1)));
+            assert!(matches!(*eval(mc, parse("let true = 1; in true"), 10).unwrap(), Expr::Int(1)));
+            assert_eq!(
+                eval(mc, parse("let x = 1; in\n  x + y"), 20).unwrap_err().kind,
+                ErrorKind::UndefinedVariable {
+                    name: "y".to_string(),
+                    pos: Pos { line: 2 }
+                }
+            );
+        })
+    }
+
+    #[test]
+    fn check_errors() {
+        assert_eq!(
+            eval_int("throw \"boom\""),
+            Err(ErrorKind::Throw {
+                message: "boom".to_string()
+            })
+        );
+        assert_eq!(
+            eval_int("1 + abort \"boom\"").unwrap_err().to_string(),
+            "evaluation aborted with the following error message: 'boom'"
+        );
+        assert_eq!(
+            eval_int("1 2"),
+            Err(ErrorKind::TypeError {
+                expected: "a function",
+                got: "an integer"
+            })
+        );
+        // `add 1 2` is fine, it's calling the result that isn't
+        assert_eq!(
+            eval_int("builtins.add 1 2 3"),
+            Err(ErrorKind::TypeError {
+                expected: "a function",
+                got: "an integer"
+            })
+        );
+        rootless_arena(|mc| {
+            let parse = |src| exprParser::new().parse(mc, Lexer::new(src, Vec::with_capacity(10), 0)).unwrap();
+            assert_eq!(
+                eval(mc, parse("let f = x: f x; in f 1"), 100).unwrap_err().kind,
+                ErrorKind::StepLimit { max_steps: 100 }
+            );
+
+            let error = eval(mc, parse("let\n  f = x: x.a;\nin\n  assert f { b = 1; } == 1;\n  2"), 100).unwrap_err();
+            assert_eq!(error.pos, Some(Pos { line: 2 }));
+            assert_eq!(
+                error.to_string(),
+                "attribute 'a' missing at line 2, did you mean 'b'?\n  \
+                 while evaluating the arguments of '=='\n  \
+                 while evaluating the condition of an assertion at line 4"
+            );
+
+            // errors without a position of their own get the closest one
+            let error = eval(mc, parse("let s = { a = 1; };\nin\n  s.${1}"), 100).unwrap_err();
+            assert_eq!(error.pos, Some(Pos { line: 3 }));
+        });
+    }
+
+    /// Parse and evaluate `src`, then hand the result to `check`. Most tests
+    /// only care about the kind of error, not the trace.
+    pub(crate) fn eval_with<R>(src: &str, check: impl for<'gc> FnOnce(Result<GcExpr<'gc>, ErrorKind>) -> R) -> R {
+        rootless_arena(|mc| {
+            let lexer = Lexer::new(src, Vec::with_capacity(10), 0);
+            let expr = exprParser::new().parse(mc, lexer).unwrap();
+            check(eval(mc, expr, 1000).map_err(|e| e.kind))
+        })
+    }
+
+    pub(crate) fn eval_int(src: &str) -> Result<i64, ErrorKind> {
+        eval_with(src, |result| {
+            result.map(|e| match *e {
+                Expr::Int(i) => i,
+                ref e => panic!("{} evaluated to {:?}", src, e),
+            })
+        })
+    }
+
+    #[test]
+    fn check_lambda() {
+        assert_eq!(eval_int("(x: x + 1) 2"), Ok(3));
+        assert_eq!(eval_int("(x: y: x * y) 3 4"), Ok(12));
+        assert_eq!(eval_int("let f = x: y: x; in f 1 2"), Ok(1));
+        assert_eq!(eval_int("let f = let y = 2; in x: x * y; y = 5; in f 3"), Ok(6));
+        assert_eq!(eval_int("let twice = f: x: f (f x); in twice (x: x * 3) 2"), Ok(18));
+    }
+
+    #[test]
+    fn check_formals() {
+        assert_eq!(eval_int("({ a, b }: a * b) { a = 2; b = 3; }"), Ok(6));
+        assert_eq!(eval_int("({ a, b ? a * 10 }: a + b) { a = 2; }"), Ok(22));
+        assert_eq!(eval_int("({ a, b ? a * 10 }: a + b) { a = 2; b = 1; }"), Ok(3));
+        assert_eq!(eval_int("({ a ? b, b ? 3 }: a) {}"), Ok(3));
+        assert_eq!(eval_int("({ a ? undefined }: 1) {}"), Ok(1));
+        assert_eq!(eval_int("({ a, ... }: a) { a = 1; b = 2; }"), Ok(1));
+        assert_eq!(eval_int("({ ... }: 1) { a = 1; }"), Ok(1));
+        assert_eq!(eval_int("(args@{ a }: a) { a = 5; }"), Ok(5));
+        assert_eq!(eval_int("let x = 4; in ({ a }: a + x) { a = x; }"), Ok(8));
+        eval_with("(args@{ a, b ? 2 }: args) { a = 1; }", |result| match *result.unwrap() {
+            Expr::AttrSet(ref set) => {
+                assert!(set.contains("a"));
+                // defaults are not part of the @-binding
+                assert!(!set.contains("b"));
+            }
+            ref e => panic!("not a set: {:?}", e),
+        });
+        eval_with("({ a, ... }@args: args) { a = 1; b = 2; }", |result| match *result.unwrap() {
+            Expr::AttrSet(ref set) => {
+                assert!(set.contains("a"));
+                assert!(set.contains("b"));
+            }
+            ref e => panic!("not a set: {:?}", e),
+        });
+        lang_test("patterns");
+    }
+
+    #[test]
+    fn check_formals_errors() {
+        assert_eq!(
+            eval_int(include_str!("lang-tests/eval-fail-missing-arg.nix")),
+            Err(ErrorKind::MissingArgument {
+                name: "y".to_string(),
+                pos: Pos { line: 1 }
+            })
+        );
+        assert_eq!(
+            eval_int(include_str!("lang-tests/eval-fail-undeclared-arg.nix")),
+            Err(ErrorKind::UnexpectedArgument {
+                name: "y".to_string(),
+                pos: Pos { line: 1 }
+            })
+        );
+        assert_eq!(
+            eval_int("({ a }: a) 1"),
+            Err(ErrorKind::TypeError {
+                expected: "a set",
+                got: "an integer"
+            })
+        );
+    }
+
+    #[test]
+    fn check_select() {
+        assert_eq!(eval_int("{ a = 1; }.a"), Ok(1));
+        assert_eq!(eval_int("{ a = { b = { c = 3; }; }; }.a.b.c"), Ok(3));
+        assert_eq!(eval_int("let s = { a = x: x * 2; }; in s.a 4"), Ok(8));
+        assert_eq!(eval_int("{ \"a b\" = 1; }.\"a b\""), Ok(1));
+        assert_eq!(eval_int("let { body = 5; }"), Ok(5));
+        assert_eq!(
+            eval_int("{ a = 1; }\n.b"),
+            Err(ErrorKind::MissingAttribute {
+                name: "b".to_string(),
+                pos: Some(Pos { line: 1 }),
+                suggestions: vec!["a".to_string()],
+            })
+        );
+        assert_eq!(
+            eval_int("{ a = 1; }.a.b"),
+            Err(ErrorKind::TypeError {
+                expected: "a set",
+                got: "an integer"
+            })
+        );
+    }
+
+    #[test]
+    fn check_inherit() {
+        assert_eq!(eval_int("let x = 1; in let inherit x; in x"), Ok(1));
+        // `inherit x;` is not `x = x;`, which would refer to itself
+        assert_eq!(eval_int("let x = 1; in let inherit x; y = x + 1; in y"), Ok(2));
+        assert_eq!(eval_int("let x = 1; y = 2; in { inherit x y; }.y"), Ok(2));
+        assert_eq!(eval_int("let x = 1; in { inherit \"x\"; }.x"), Ok(1));
+        assert_eq!(eval_int("let s = { a = 1; b = 2; }; in let inherit (s) a b; in a + b"), Ok(3));
+        assert_eq!(eval_int("{ inherit ({ a = 3; }) a; }.a"), Ok(3));
+        // the source of `inherit (...)` sees the scope it's in
+        assert_eq!(eval_int("let inherit (s) a; s = { a = 4; }; in a"), Ok(4));
+        assert_eq!(
+            eval_int("let inherit ({ a = 1; }) b; in b"),
+            Err(ErrorKind::MissingAttribute {
+                name: "b".to_string(),
+                pos: Some(Pos { line: 1 }),
+                suggestions: vec!["a".to_string()],
+            })
+        );
+        assert_eq!(
+            eval_int("let inherit y; in y"),
+            Err(ErrorKind::UndefinedVariable {
+                name: "y".to_string(),
+                pos: Pos { line: 1 }
+            })
+        );
+    }
+
+    #[test]
+    #[ignore = "needs `derivation`, which is not implemented yet"]
+    fn check_delayed_with_inherit() {
+        lang_test("delayed-with-inherit");
+    }
+
+    /// `eval-okay-delayed-with-inherit` with sets that only pretend to be
+    /// derivations. Building one would force its `builder`.
+    #[test]
+    fn check_delayed_with_inherit_without_derivation() {
+        let src = r#"
+            let
+              derivation = attrs: attrs // { drvAttrs = attrs; };
+              pkgs_ = with pkgs; {
+                a = derivation { name = "a"; builder = throw "a got built"; inherit b; };
+                inherit b;
+              };
+              packageOverrides = p: {
+                b = derivation { name = "b-overridden"; builder = assert false; "b"; };
+              };
+              pkgs = pkgs_ // (packageOverrides pkgs_);
+            in pkgs.a.b.name
+        "#;
+        assert_eq!(eval_strict(src).unwrap(), r#""b-overridden""#);
+    }
+
+    #[test]
+    fn check_inherit_from_is_shared() {
+        rootless_arena(|mc| {
+            let lexer = Lexer::new("let inherit (s) a b; s = {}; in 1", Vec::with_capacity(10), 0);
+            let bindings = match *exprParser::new().parse(mc, lexer).unwrap() {
+                Expr::Let { ref bindings, .. } => bindings.clone(),
+                ref e => panic!("not a let: {:?}", e),
+            };
+            let env = Env::new_rec(mc, Gc::allocate(mc, Env::new_root()), bindings);
+            let from = |name| match *env.lookup(name).unwrap() {
+                Expr::Thunk { t } => match *t.read() {
+                    ThunkState::Suspended { expr, .. } => match *expr {
+                        Expr::InheritFrom { from, .. } => from,
+                        ref e => panic!("not an inherit: {:?}", e),
+                    },
+                    ref s => panic!("not suspended: {:?}", s),
+                },
+                ref e => panic!("not a thunk: {:?}", e),
+            };
+            assert!(Gc::ptr_eq(from("a"), from("b")));
+        })
+    }
+
+    #[test]
+    fn check_attrs() {
+        assert_eq!(eval_int("{ a.b.c = 1; a.d = 2; }.a.b.c"), Ok(1));
+        assert_eq!(eval_int("{ a.b.c = 1; a.d = 2; }.a.d"), Ok(2));
+        assert_eq!(eval_int("{ a = { b = 1; }; a.c = 2; }.a.b"), Ok(1));
+        assert_eq!(eval_int("{ a.c = 2; a = { b = 1; }; }.a.c"), Ok(2));
+        assert_eq!(eval_int("rec { a = b; b = 2; }.a"), Ok(2));
+        assert_eq!(eval_int("rec { a.b = c; c = 3; }.a.b"), Ok(3));
+        // only `rec` sets see themselves
+        assert_eq!(eval_int("let b = 1; in { a = b; b = 2; }.a"), Ok(1));
+        assert_eq!(eval_int("let a.b = 1; a.c = 2; in a.b + a.c"), Ok(3));
+        assert_eq!(eval_int("let { a = 1; body = a; }"), Ok(1));
+        assert_eq!(eval_int("let x = \"a\"; in { ${x} = 4; }.a"), Ok(4));
+        assert_eq!(eval_int("let x = \"a\"; in { ${x}.b = 5; }.a.b"), Ok(5));
+        assert_eq!(eval_int("rec { ${\"b\"} = a; a = 6; }.b"), Ok(6));
+        eval_with("{ ${null} = true; }", |result| match *result.unwrap() {
+            Expr::AttrSet(ref set) => assert!(set.is_empty()),
+            ref e => panic!("not a set: {:?}", e),
+        });
+        lang_test("null-dynamic-attrs");
+        assert_eq!(
+            eval_int("let k = \"a\"; in { a = 1;\n ${k} = 2; }.a"),
+            Err(ErrorKind::DuplicateAttribute {
+                name: "a".to_string(),
+                pos: Pos { line: 2 }
+            })
+        );
+        assert_eq!(
+            eval_int("{ ${1} = 2; }.a"),
+            Err(ErrorKind::TypeError {
+                expected: "a string",
+                got: "an integer"
+            })
+        );
+    }
+
+    #[test]
+    fn check_select_dynamic() {
+        assert_eq!(eval_int("let k = \"a\"; in { a = 1; }.${k}"), Ok(1));
+        assert_eq!(eval_int("let k = \"b\"; in { a.b.c = 2; }.a.${k}.c"), Ok(2));
+        assert_eq!(eval_int("let s = { k = \"a\"; }; in { a = 3; }.${s.k}"), Ok(3));
+        assert_eq!(
+            eval_int("{ a = 1; }.${null}"),
+            Err(ErrorKind::TypeError {
+                expected: "a string",
+                got: "null"
+            })
+        );
+    }
+
+    #[test]
+    fn check_select_or() {
+        assert_eq!(eval_int("{ a = 1; }.a or 2"), Ok(1));
+        assert_eq!(eval_int("{ a = 1; }.b or 2"), Ok(2));
+        assert_eq!(eval_int("{ a.b = 1; }.a.c or 2"), Ok(2));
+        assert_eq!(eval_int("{ a = 1; }.a.b or 2"), Ok(2));
+        assert_eq!(eval_int("let x = 3; in (1).a or x"), Ok(3));
+        assert_eq!(eval_int("let k = \"c\"; in { }.${k} or { }.d or 4"), Ok(4));
+        // `or` not following a select is just a variable
+        assert_eq!(eval_int("let or = x: y: x + y; in (f: f) or 1 4"), Ok(5));
+        // the default is only evaluated when needed
+        assert_eq!(eval_int("{ a = 1; }.a or undefined"), Ok(1));
+    }
+
+    #[test]
+    fn check_did_you_mean() {
+        let err = eval_int("{ foo = 1; fob = 2; bar = 3; }.fo").unwrap_err();
+        assert_eq!(
+            err,
+            ErrorKind::MissingAttribute {
+                name: "fo".to_string(),
+                pos: Some(Pos { line: 1 }),
+                suggestions: vec!["fob".to_string(), "foo".to_string()],
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "attribute 'fo' missing at line 1, did you mean one of 'fob', 'foo'?"
+        );
+        assert_eq!(
+            eval_int("{ version = 1; }.verison").unwrap_err().to_string(),
+            "attribute 'verison' missing at line 1, did you mean 'version'?"
+        );
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("abc", "abc"), 0);
+    }
+
+    pub(crate) fn eval_bool(src: &str) -> Result<bool, ErrorKind> {
+        eval_with(src, |result| {
+            result.map(|e| match *e {
+                Expr::Bool(b) => b,
+                ref e => panic!("{} evaluated to {:?}", src, e),
+            })
+        })
+    }
+
+    #[test]
+    fn check_has_attr() {
+        assert_eq!(eval_bool("{ a = 1; } ? a"), Ok(true));
+        assert_eq!(eval_bool("{ a = 1; } ? b"), Ok(false));
+        assert_eq!(eval_bool("{ a.b.c = 1; } ? a.b.c"), Ok(true));
+        assert_eq!(eval_bool("{ a.b.c = 1; } ? a.b.d"), Ok(false));
+        assert_eq!(eval_bool("{ a.b.c = 1; } ? a.b.c.d"), Ok(false));
+        assert_eq!(eval_bool("{ a = 1; } ? a.b"), Ok(false));
+        assert_eq!(eval_bool("null ? a"), Ok(false));
+        assert_eq!(eval_bool("let k = \"b\"; in { a.b = 1; } ? a.${k}"), Ok(true));
+        assert_eq!(eval_bool("let k = \"c\"; in { a.b = 1; } ? ${k}"), Ok(false));
+        // only the sets along the path are evaluated
+        assert_eq!(eval_bool("{ a = undefined; } ? a"), Ok(true));
+        assert!(eval_bool("{ a = undefined; } ? a.b").is_err());
+        assert!(eval_bool("let k = 1; in { } ? ${k}").is_err());
+    }
+
+    #[test]
+    fn check_with() {
+        assert_eq!(eval_int("with { a = 1; }; a"), Ok(1));
+        assert_eq!(eval_int(include_str!("lang-tests/eval-okay-nested-with.nix")), Ok(2));
+        assert_eq!(
+            eval_strict(include_str!("lang-tests/eval-okay-with.nix")).unwrap(),
+            include_str!("lang-tests/eval-okay-with.exp").trim_end()
+        );
+        assert_eq!(eval_int("with { a = 1; }; with { b = 2; }; a + b"), Ok(3));
+        // lexical bindings win, no matter how far out
+        assert_eq!(eval_int("let a = 1; in with { a = 2; }; a"), Ok(1));
+        assert_eq!(eval_int("with { a = 2; }; (a: a) 3"), Ok(3));
+        assert_eq!(eval_int("with { a = 2; }; let b = a; in with { a = 4; }; b"), Ok(2));
+        assert_eq!(eval_int("with { a = 1; }; { inherit a; }.a"), Ok(1));
+        // the set is only evaluated if a variable isn't bound lexically
+        assert_eq!(eval_int("with undefined; 1"), Ok(1));
+        assert_eq!(eval_int("let x = 1; in with undefined; x"), Ok(1));
+        assert_eq!(eval_int("let s = { a = 1; b = 2; }; in with s; a + b"), Ok(3));
+        assert_eq!(
+            eval_int("with { a = 1; };\n b"),
+            Err(ErrorKind::UndefinedVariable {
+                name: "b".to_string(),
+                pos: Pos { line: 2 }
+            })
+        );
+        assert_eq!(
+            eval_int("with 1; a"),
+            Err(ErrorKind::TypeError {
+                expected: "a set",
+                got: "an integer"
+            })
+        );
+    }
+
+    #[test]
+    #[ignore = "needs `derivation`, which is not implemented yet"]
+    fn check_delayed_with() {
+        lang_test("delayed-with");
+    }
+
+    /// `eval-okay-delayed-with` with sets that only pretend to be
+    /// derivations, see `check_delayed_with_inherit_without_derivation`.
+    #[test]
+    fn check_delayed_with_without_derivation() {
+        let src = r#"
+            let
+              derivation = attrs: attrs // { drvAttrs = attrs; };
+              pkgs_ = with pkgs; {
+                a = derivation { name = "a"; builder = throw "a got built"; inherit b; };
+                b = derivation { name = "b"; builder = assert false; "b"; inherit a; };
+                c = b;
+              };
+              packageOverrides = pkgs: with pkgs; {
+                b = derivation (b.drvAttrs // { name = "${b.name}-overridden"; });
+              };
+              pkgs = pkgs_ // (packageOverrides pkgs_);
+            in "${pkgs.a.b.name} ${pkgs.c.name} ${pkgs.b.a.name}"
+        "#;
+        assert_eq!(eval_strict(src).unwrap(), r#""b-overridden b-overridden a""#);
+    }
+
+    #[test]
+    fn check_scopes() {
+        assert_eq!(eval_int(include_str!("lang-tests/eval-okay-scope-1.nix")), Ok(3));
+        assert_eq!(eval_int(include_str!("lang-tests/eval-okay-scope-2.nix")), Ok(1));
+        assert_eq!(eval_int(include_str!("lang-tests/eval-okay-scope-3.nix")), Ok(4));
+        assert_eq!(eval_int(include_str!("lang-tests/eval-okay-scope-7.nix")), Ok(1));
+    }
+
+    #[test]
+    fn check_if_assert() {
+        assert_eq!(eval_int("if true then 1 else 2"), Ok(1));
+        assert_eq!(eval_int("if false then 1 else 2"), Ok(2));
+        assert_eq!(eval_int("if { a = true; }.a then 1 else undefined"), Ok(1));
+        assert_eq!(
+            eval_int("if 1 then 1 else 2"),
+            Err(ErrorKind::TypeError {
+                expected: "a Boolean",
+                got: "an integer"
+            })
+        );
+        assert_eq!(eval_int("assert true; 1"), Ok(1));
+        assert_eq!(
+            eval_int("let x = 1; in\n  assert false; x"),
+            Err(ErrorKind::AssertionFailed { pos: Pos { line: 2 } })
+        );
+        assert_eq!(
+            eval_int("assert null; 1"),
+            Err(ErrorKind::TypeError {
+                expected: "a Boolean",
+                got: "null"
+            })
+        );
+    }
+
+    #[test]
+    fn check_bool_ops() {
+        for &(src, expected) in &[
+            ("true && true", true),
+            ("true && false", false),
+            ("false && undefined", false),
+            ("false || true", true),
+            ("false || false", false),
+            ("true || undefined", true),
+            ("false -> undefined", true),
+            ("true -> false", false),
+            ("true -> true", true),
+            ("!true", false),
+            ("!false", true),
+            ("!!true", true),
+            ("true && !false", true),
+            ("false -> true -> false", true),
+        ] {
+            assert_eq!(eval_bool(src), Ok(expected), "{}", src);
+        }
+        // the right side still has to be a boolean when it's needed
+        assert_eq!(
+            eval_bool("true && 1"),
+            Err(ErrorKind::TypeError {
+                expected: "a Boolean",
+                got: "an integer"
+            })
+        );
+        assert_eq!(
+            eval_bool("1 || true"),
+            Err(ErrorKind::TypeError {
+                expected: "a Boolean",
+                got: "an integer"
+            })
+        );
+        assert!(eval_bool("!1").is_err());
+    }
+
+    #[test]
+    fn check_arithmetic() {
+        for &(src, expected) in &[
+            ("123 + 456", 579),
+            ("let x = 12; in 0 + -10 + -(-11) + -x", -11),
+            ("10 - 7 - -2", 5),
+            ("10 - (6 - -1)", 3),
+            ("10 - 1 + 2", 11),
+            ("3 * 4 * 5", 60),
+            ("56088 / 123 / 2", 228),
+            ("3 + 4 * 5 - 6 / 2", 20),
+            ("7 / 2", 3),
+            ("-7 / 2", -3),
+            ("7 / -2", -3),
+            ("builtins.bitAnd 12 10", 8),
+            ("builtins.bitOr 12 10", 14),
+            ("builtins.bitXor 12 10", 6),
+        ] {
+            assert_eq!(eval_int(src), Ok(expected), "{}", src);
+        }
+        assert_eq!(eval_int("1 / 0"), Err(ErrorKind::DivisionByZero));
+        assert_eq!(
+            eval_int("9223372036854775807 + 1"),
+            Err(ErrorKind::IntegerOverflow {
+                op: "+".to_string(),
+                left: i64::MAX,
+                right: 1
+            })
+        );
+        assert!(eval_int("-9223372036854775807 - 2").is_err());
+        assert!(eval_int("4611686018427387904 * 2").is_err());
+        assert_eq!(
+            eval_int("1 + true"),
+            Err(ErrorKind::TypeError {
+                expected: "an integer",
+                got: "a Boolean"
+            })
+        );
+        lang_test("arithmetic");
+    }
+
+    #[test]
+    fn check_float_arithmetic() {
+        let eval_float = |src: &str| {
+            eval_with(src, |result| match *result.unwrap() {
+                Expr::Float(f) => f,
+                ref e => panic!("{} evaluated to {:?}", src, e),
+            })
+        };
+        assert_eq!(eval_float("1.5 + 1"), 2.5);
+        assert_eq!(eval_float("1 - 1.5"), -0.5);
+        assert_eq!(eval_float("2.5 * 2"), 5.0);
+        assert_eq!(eval_float("7 / 2.0"), 3.5);
+        assert_eq!(eval_float("-1.5"), -1.5);
+        assert_eq!(eval_int("1.0 / 0"), Err(ErrorKind::DivisionByZero));
+    }
+
+    #[test]
+    fn check_comparisons() {
+        for &(src, expected) in &[
+            ("3 < 7", true),
+            ("7 < 3", false),
+            ("3 < 3", false),
+            ("3 <= 7", true),
+            ("7 <= 3", false),
+            ("3 <= 3", true),
+            ("3 > 7", false),
+            ("7 > 3", true),
+            ("3 > 3", false),
+            ("3 >= 7", false),
+            ("7 >= 3", true),
+            ("3 >= 3", true),
+            ("2 > 1 == 1 < 2", true),
+            ("1 + 2 * 3 >= 7", true),
+            ("1 < 1.5", true),
+            ("1.5 < 1", false),
+            ("\"aa\" < \"ab\"", true),
+            ("\"aa\" < \"aa\"", false),
+            ("\"foo\" < \"foobar\"", true),
+            ("./a < ./b", true),
+            ("[] < [1]", true),
+            ("[1] < []", false),
+            ("[1 2] < [1 3]", true),
+            ("[1 2] < [1 2 0]", true),
+            ("[1 3] < [1 2 0]", false),
+            ("let x = 1; in [x (x + 1)] < [1 (x + 2)]", true),
+            ("[1 undefined] < [2 undefined]", true),
+            ("1 == 1", true),
+            ("1 == 1.0", true),
+            ("1 != 2", true),
+            ("1 == \"1\"", false),
+            ("null == null", true),
+            ("true != false", true),
+            ("\"a\" == \"a\"", true),
+            ("(x: x) == (x: x)", false),
+        ] {
+            assert_eq!(eval_bool(src), Ok(expected), "{}", src);
+        }
+        assert_eq!(
+            eval_bool("1 < \"a\""),
+            Err(ErrorKind::CannotCompare {
+                left: "an integer",
+                right: "a string"
+            })
+        );
+        assert!(eval_bool("[1] < [\"a\"]").is_err());
+    }
+
+    #[test]
+    fn check_concat_and_update() {
+        assert_eq!(
+            eval_strict(include_str!("lang-tests/eval-okay-concat.nix")).unwrap(),
+            include_str!("lang-tests/eval-okay-concat.exp").trim_end()
+        );
+        assert_eq!(eval_strict("[] ++ []").unwrap(), "[ ]");
+        assert_eq!(eval_strict("let x = 2; in [1] ++ [x] ++ []").unwrap(), "[ 1 2 ]");
+        // elements aren't forced
+        assert_eq!(eval_bool("builtins.elem 1 ([1] ++ [undefined])"), Ok(true));
+        assert_eq!(eval_strict("{ a = 1; b = 2; } // { b = 3; c = 4; }").unwrap(), "{ a = 1; b = 3; c = 4; }");
+        assert_eq!(eval_strict("{ a = 1; } // { a = 2; b = 3; c = 4; }").unwrap(), "{ a = 2; b = 3; c = 4; }");
+        assert_eq!(eval_strict("{ a = 1; } // { } // { }").unwrap(), "{ a = 1; }");
+        // shallow, i.e. nested sets are replaced rather than merged
+        assert_eq!(eval_strict("{ a.b = 1; } // { a.c = 2; }").unwrap(), "{ a = { c = 2; }; }");
+        assert_eq!(eval_int("({ a = undefined; } // { b = 1; }).b"), Ok(1));
+        assert!(eval_strict("[1] ++ { }").is_err());
+        assert!(eval_strict("{ } // [1]").is_err());
+    }
+
+    #[test]
+    fn check_interpolation() {
+        for &(src, expected) in &[
+            (r#""foo${"bar"}""#, r#""foobar""#),
+            (r#"let x = "b"; in "a${x}c${x}""#, r#""abcb""#),
+            (r#""foo${if true then "b${"a" + "r"}" else "xyzzy"}blaat""#, r#""foobarblaat""#),
+            (r#""${{ outPath = "/x"; }}""#, r#""/x""#),
+            (r#""${{ __toString = self: "s${self.a}"; a = "1"; }}""#, r#""s1""#),
+            (r#""${{ __toString = self: { outPath = "o"; }; }}""#, r#""o""#),
+            (r#""a" + "b" + "c""#, r#""abc""#),
+            (r#"{ outPath = "a"; } + "b""#, r#""ab""#),
+            (r#""a" + { outPath = "b"; }"#, r#""ab""#),
+            ("/a/b + /c/d", "/a/b/c/d"),
+            (r#"/foo/bar + "/../xyzzy/." + "/foo.txt""#, "/foo/xyzzy/foo.txt"),
+            (r#""/../foo" + toString /x/y"#, r#""/../foo/x/y""#),
+            ("toString 1", r#""1""#),
+            ("toString 1.5", r#""1.500000""#),
+            ("toString (1.0 / 3)", r#""0.333333""#),
+            ("toString 1.0e20", r#""100000000000000000000.000000""#),
+            ("toString [ 0.5 1 ]", r#""0.500000 1""#),
+            ("toString null", r#""""#),
+            ("toString true + toString false", r#""1""#),
+            (r#"toString [ 1 [ 2 3 ] "a" ]"#, r#""1 2 3 a""#),
+            ("toString [ [ ] 1 ]", r#""1""#),
+            (r#"toString { __toString = self: 42; }"#, r#""42""#),
+        ] {
+            assert_eq!(eval_strict(src).unwrap(), expected, "{}", src);
+        }
+        let src = format!(r#""${{{}/src}}""#, env!("CARGO_MANIFEST_DIR"));
+        let store_path = eval_strict(&src).unwrap();
+        let hash = &store_path["\"/nix/store/".len()..store_path.len() - "-src\"".len()];
+        assert!(store_path.starts_with("\"/nix/store/") && store_path.ends_with("-src\""), "{}", store_path);
+        assert!(hash.len() == 32 && hash.bytes().all(|c| c.is_ascii_alphanumeric()), "{}", store_path);
+        assert_eq!(eval_strict(&src).unwrap(), store_path);
+        assert_eq!(
+            eval_strict(r#""${./src}""#).unwrap_err().to_string(),
+            "cannot copy './src' to the store: relative paths aren't supported yet"
+        );
+        // the name is the same, so the contents make the difference
+        let dir = std::env::temp_dir().join(format!("trix-store-{}", std::process::id()));
+        for (sub, contents) in &[("a", "one"), ("b", "two"), ("c", "one")] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+            std::fs::write(dir.join(sub).join("file"), contents).unwrap();
+        }
+        let store_path = |sub: &str| eval_strict(&format!(r#""${{{}/{}/file}}""#, dir.display(), sub)).unwrap();
+        assert_ne!(store_path("a"), store_path("b"));
+        assert_eq!(store_path("a"), store_path("c"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        // `builtins.hashString "sha256" ""` in nix's base 32
+        assert_eq!(
+            nix_base32(&sha2::Sha256::digest(b"")),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+
+        assert_eq!(eval_strict(r#""${1}""#), Err(ErrorKind::CannotCoerce { got: "an integer" }));
+        assert_eq!(eval_strict(r#""a" + 1"#), Err(ErrorKind::CannotCoerce { got: "an integer" }));
+        assert_eq!(eval_strict(r#""${{ }}""#), Err(ErrorKind::CannotCoerce { got: "a set" }));
+        assert_eq!(
+            eval_strict(r#""${[]}""#).unwrap_err().to_string(),
+            "cannot coerce a list to a string"
+        );
+        for src in &[
+            include_str!("lang-tests/eval-fail-bad-antiquote-1.nix"),
+            include_str!("lang-tests/eval-fail-bad-antiquote-3.nix"),
+        ] {
+            assert_eq!(eval_strict(src), Err(ErrorKind::CannotCoerce { got: "a function" }), "{}", src);
+        }
+        assert!(matches!(
+            eval_strict(include_str!("lang-tests/eval-fail-bad-antiquote-2.nix")),
+            Err(ErrorKind::CannotCopyToStore { .. })
+        ));
+    }
+
+    #[test]
+    fn check_string_literals() {
+        for name in &[
+            "eval-okay-string",
+            "eval-okay-ind-string",
+            "eval-okay-backslash-newline-1",
+            "eval-okay-backslash-newline-2",
+        ] {
+            let path = format!("./src/lang-tests/{}", name);
+            let src = std::fs::read_to_string(format!("{}.nix", path)).unwrap();
+            let exp = std::fs::read_to_string(format!("{}.exp", path)).unwrap();
+            assert_eq!(eval_strict(&src).unwrap(), exp.trim_end(), "{}", name);
+        }
+        let crlf = include_str!("lang-tests/parse-okay-crlf.nix");
+        assert_eq!(
+            eval_strict(&format!("({}).foo", crlf)).unwrap(),
+            r#""multi\nline\n  string\n  test\r""#
+        );
+        for &(src, expected) in &[
+            (r#""\"\\\$\${x}\a""#, r#""\"\\$${x}a""#),
+            ("''\n  a\n    b\n  ''", r#""a\n  b\n""#),
+            ("''  a\n  b''", r#""a\nb""#),
+            ("''\n  a\n  ${\"b\"}\n''", r#""a\nb\n""#),
+            // escapes aren't indentation
+            ("''\n    a\n  ''\\n  b\n  ''", r#""  a\n\n  b\n""#),
+            ("''''", r#""""#),
+        ] {
+            assert_eq!(eval_strict(src).unwrap(), expected, "{}", src);
+        }
+    }
+
+    #[test]
+    fn check_deep_equality() {
+        for &(src, expected) in &[
+            ("[] == []", true),
+            ("[1 2] == [1 2]", true),
+            ("[1 2] == [1 2.0]", true),
+            ("[1 2] == [2 1]", false),
+            ("[1 2] == [1 2 3]", false),
+            ("[[1 2] [3]] == [[1 2] [3]]", true),
+            ("[[1 2] [3]] != [[1 2] [4]]", true),
+            ("let x = 1; in [x (x + 1)] == [1 2]", true),
+            ("[1 undefined] == [2 undefined]", false),
+            ("{ } == { }", true),
+            ("{ a = 1; b = [ 2 ]; } == { b = [ 2 ]; a = 1; }", true),
+            ("{ a = 1; } == { a = 1; b = 2; }", false),
+            ("{ a = 1; } == { b = 1; }", false),
+            ("rec { x = 1; y = x; } == { x = 1; y = 1; }", true),
+            ("{ a = { b = 1; }; } == { a = { b = 2; }; }", false),
+            ("{ f = x: x; } == { f = x: x; }", false),
+            ("let s = { f = x: x; }; in s == s", true),
+            ("[1] == { }", false),
+            // derivations only compare their outPath
+            (
+                "{ type = \"derivation\"; outPath = \"/a\"; x = 1; } == { type = \"derivation\"; outPath = \"/a\"; }",
+                true,
+            ),
+            ("{ type = \"derivation\"; outPath = \"/a\"; } == { type = \"derivation\"; outPath = \"/b\"; }", false),
+            ("{ type = \"other\"; outPath = \"/a\"; x = 1; } == { type = \"other\"; outPath = \"/a\"; }", false),
+            // even when `type` is still a thunk
+            ("let d = x: { type = \"deriv\" + \"ation\"; outPath = \"/a\"; inherit x; }; in d 1 == d 2", true),
+            ("[ [ 1 2 ] [ 3 ] ] < [ [ 1 2 ] [ (2 + 2) ] ]", true),
+            ("[ [ 1 ] ] < [ [ 1 ] 2 ]", true),
+            ("[ 1 (1 + 1) ] >= [ 1 2 undefined ]", false),
+            ("builtins.elem 2 [1 2 3]", true),
+            ("builtins.elem 4 [1 2 3]", false),
+            ("builtins.elem [1] [[0] [1]]", true),
+            ("builtins.elem 1 [1 undefined]", true),
+        ] {
+            assert_eq!(eval_bool(src), Ok(expected), "{}", src);
+        }
+        assert_eq!(
+            eval_bool(include_str!("lang-tests/eval-fail-assert.nix")),
+            Err(ErrorKind::AssertionFailed { pos: Pos { line: 2 } })
+        );
+    }
+
+    #[test]
+    #[ignore = "needs `derivation`, which is not implemented yet"]
+    fn check_eq_derivations() {
+        lang_test("eq-derivations");
+    }
+
+    #[test]
+    fn check_nested_comparisons() {
+        for &(src, expected) in &[
+            ("{ a = [ (1 + 1) ]; } == { a = [ 2 ]; }", true),
+            ("let x = 1 + 1; in { a.b = [ x [ x ] ]; c = x; } == { c = 2; a.b = [ 2 [ (3 - 1) ] ]; }", true),
+            ("[ [ 1 ] { a = 1 + 1; } ] == [ [ 1 ] { a = 3; } ]", false),
+            ("[ [ 1 (1 + 1) ] [ 3 ] ] < [ [ 1 2 ] [ (2 + 2) ] ]", true),
+            ("builtins.elem { a = [ (1 + 1) ]; } [ { a = [ 1 ]; } { a = [ 2 ]; } ]", true),
+            // like eval-okay-eq-derivations, only `outPath` counts
+            (r#"{ type = "derivation"; outPath = "/a"; x = 1; } == { type = "derivation"; outPath = "/a"; }"#, true),
+            (r#"{ type = "derivation"; outPath = "/a"; } == { type = "derivation"; outPath = "/b"; }"#, false),
+        ] {
+            assert_eq!(eval_bool(src), Ok(expected), "{}", src);
+        }
+        // forcing an element fails the whole comparison with the same error
+        let boom = Err(ErrorKind::Throw {
+            message: "boom".to_string(),
+        });
+        for src in &[
+            r#"{ a = [ (throw "boom") ]; } == { a = [ 1 ]; }"#,
+            r#"[ 1 [ (throw "boom") ] ] < [ 1 [ 2 ] ]"#,
+            r#"builtins.genList (i: i) 20 ++ [ (throw "boom") ] == builtins.genList (i: i) 21"#,
+        ] {
+            assert_eq!(eval_bool(src), boom, "{}", src);
+        }
+    }
+
+    /// Evaluate `src` including everything inside it and print the result,
+    /// like `nix-instantiate --eval --strict`.
+    pub(crate) fn eval_strict(src: &str) -> Result<String, ErrorKind> {
+        fn force_deep<'gc>(mc: MutationContext<'gc, '_>, value: GcExpr<'gc>) -> Result<(), ErrorKind> {
+            match *value {
+                Expr::List(ref elems) => {
+                    for elem in elems {
+                        force_deep(mc, eval(mc, *elem, 1000).map_err(|e| e.kind)?)?;
+                    }
+                }
+                Expr::AttrSet(ref attrs) => {
+                    for (_, value) in attrs.iter() {
+                        force_deep(mc, eval(mc, *value, 1000).map_err(|e| e.kind)?)?;
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+        rootless_arena(|mc| {
+            let lexer = Lexer::new(src, Vec::with_capacity(10), 0);
+            let value = eval(mc, exprParser::new().parse(mc, lexer).unwrap(), 10_000).map_err(|e| e.kind)?;
+            force_deep(mc, value)?;
+            Ok(value.to_string())
+        })
+    }
+
+    /// Run `lang-tests/eval-okay-${name}.nix` and compare what it evaluates
+    /// to with its `.exp` file. There is no `import` yet, so `lib.nix` is
+    /// pasted in where the test imports it.
+    pub(crate) fn lang_test(name: &str) {
+        let src = std::fs::read_to_string(format!("./src/lang-tests/eval-okay-{}.nix", name)).unwrap();
+        let lib = format!("with ({});", include_str!("lang-tests/lib.nix"));
+        let src = src.replace("with import ./lib.nix;", &lib);
+        let expected = std::fs::read_to_string(format!("./src/lang-tests/eval-okay-{}.exp", name)).unwrap();
+        assert_eq!(eval_strict(&src), Ok(expected.trim().to_string()), "{}", name);
+    }
+
+    #[test]
+    fn check_floats() {
+        assert_eq!(
+            eval_strict(include_str!("lang-tests/eval-okay-float.nix")).unwrap(),
+            include_str!("lang-tests/eval-okay-float.exp").trim_end()
+        );
+        assert_eq!(eval_strict("[ 1.5 .5 1. 2.5e3 1.0e-3 ]").unwrap(), "[ 1.5 0.5 1 2500 0.001 ]");
+        assert_eq!(eval_strict("[ 1.0e20 1.0e-20 (1.5 * 1.0e19) ]").unwrap(), "[ 1e+20 1e-20 1.5e+19 ]");
+    }
+
+    #[test]
+    fn check_format_float() {
+        for &(x, expected) in &[
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (1.5, "1.5"),
+            (3.4, "3.4"),
+            (-2.25, "-2.25"),
+            (100000.0, "100000"),
+            (1000000.0, "1e+06"),
+            (1e20, "1e+20"),
+            (1.5e-7, "1.5e-07"),
+            (0.0001, "0.0001"),
+            (0.00001, "1e-05"),
+            (123456789.0, "1.23457e+08"),
+            (1.0 / 3.0, "0.333333"),
+            (999999.5, "1e+06"),
+            (1e100, "1e+100"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ] {
+            assert_eq!(crate::expr::format_float(x), expected);
+        }
+    }
+
+    #[test]
+    fn check_print_values() {
+        assert_eq!(
+            eval_strict("{ a = 1; \"b c\" = [ null true ]; d = { }; }").unwrap(),
+            "{ a = 1; \"b c\" = [ null true ]; d = { }; }"
+        );
+        assert_eq!(eval_strict("\"a\n\tb\"").unwrap(), "\"a\\n\\tb\"");
+        assert_eq!(eval_strict("[ (x: x) map (builtins.add 1) ]").unwrap(), "[ <LAMBDA> <PRIMOP> <PRIMOP-APP> ]");
     }
 }
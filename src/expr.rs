@@ -1,40 +1,80 @@
-use gc_arena::{make_arena, ArenaParameters, Collect, Gc, GcCell};
+use crate::host::HostFn;
+use gc_arena::{make_arena, Collect, Gc, GcCell, MutationContext};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt;
 
 /// Placeholder for e.g. argument names in lambdas (x, y, i)
 pub type Symbol = String;
 
+/// Where an expression starts in the source, used for error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Collect)]
+#[collect(require_static)]
+pub struct Pos {
+    pub line: usize,
+}
+
+impl Pos {
+    /// `location` is what the lexer hands to the parser, i.e. a 0-based line.
+    pub fn new(location: usize) -> Pos {
+        Pos { line: location + 1 }
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}", self.line)
+    }
+}
+
 #[derive(Debug, Clone, Collect)]
 #[collect(no_drop)]
-///
 pub enum Expr<'gc> {
     Null(),
     Int(i64),
 
-    // TODO decide how to handle floats
-    Float(String),
+    Float(f64),
     Bool(bool),
-    Var(Symbol),
+    Var(Symbol, Pos),
 
     // TODO should probably keep `Formal` in a separate structure
     Formal(Symbol, Option<GcExpr<'gc>>),
-    InheritedVar(Symbol),
+    // `inherit x;` binds `x` to `InheritedVar(x)`, which is looked up like a
+    // `Var` but outside of the (possibly recursive) scope being built.
+    InheritedVar(Symbol, Pos),
+    // `inherit (from) x y;` selects `x` and `y` from `from`. Both bindings
+    // point at the same `from`, which is only evaluated once.
+    InheritFrom {
+        from: GcExpr<'gc>,
+        name: Symbol,
+        pos: Pos,
+    },
     String(String),
 
     // Interpolated strings are made up of expressions (either Expr::String or
     // some other expression that must evaluate to Expr::String)
     InterpolatedString(Vec<GcExpr<'gc>>),
     Path(String),
+    // Elements are always closed, see `Expr::is_closed`.
     List(Vec<GcExpr<'gc>>),
+    // A list as written, i.e. `[ a (f b) ]`, which evaluates to a `List`.
+    ListLiteral(Vec<GcExpr<'gc>>),
+    // The value `Attrs` evaluate to.
+    AttrSet(AttrSet<'gc>),
+    // An attribute set as written. The parser merges nested paths like
+    // `a.b = 1; a.c = 2;` into nested `Attrs`, so only single names are left.
     Attrs {
+        attrs: Vec<(Symbol, GcExpr<'gc>, Pos)>,
         // unfortunately left-side attributes can be dynamic, e.g.
-        // let xx = "xx"; in { ${xx} = 2; } is totally valid.
-        attrs: Vec<(Vec<GcExpr<'gc>>, GcExpr<'gc>)>,
+        // let xx = "xx"; in { ${xx} = 2; } is totally valid. These are only
+        // known after evaluating the key.
+        dynamic: Vec<(GcExpr<'gc>, GcExpr<'gc>, Pos)>,
         recursive: bool,
     },
     Assert {
         expr: GcExpr<'gc>,
         body: GcExpr<'gc>,
+        pos: Pos,
     },
     With {
         expr: GcExpr<'gc>,
@@ -48,8 +88,15 @@ pub enum Expr<'gc> {
     Lambda {
         arg: Option<Symbol>,
         body: GcExpr<'gc>,
-        // bool == has ellipsis ...
-        formals: (Vec<GcExpr<'gc>>, bool),
+        // None for a plain `x: body` lambda, otherwise the `{ a, b ? 1 }`
+        // pattern. bool == has ellipsis ...
+        formals: Option<(Vec<GcExpr<'gc>>, bool)>,
+        pos: Pos,
+    },
+    // A lambda together with the env it was defined in.
+    Closure {
+        lambda: GcExpr<'gc>,
+        env: GcEnv<'gc>,
     },
     App {
         f: GcExpr<'gc>,
@@ -59,11 +106,14 @@ pub enum Expr<'gc> {
     Select {
         expr: GcExpr<'gc>,
         attr_path: Vec<GcExpr<'gc>>,
+        pos: Pos,
     },
+    // `expr.attr_path or default`
     SelectOr {
         expr: GcExpr<'gc>,
-        attr_path_left: Vec<GcExpr<'gc>>,
-        attr_path_right: Vec<GcExpr<'gc>>,
+        attr_path: Vec<GcExpr<'gc>>,
+        default: GcExpr<'gc>,
+        pos: Pos,
     },
     Pap {
         f: GcExpr<'gc>,
@@ -71,13 +121,16 @@ pub enum Expr<'gc> {
         arity: usize,
     },
     Thunk {
-        t: GcExpr<'gc>,
+        t: GcThunk<'gc>,
     },
     PrimOp {
         name: &'gc str,
         arity: usize,
     },
-    HasAttr { // specialized primop, might not need, to be decided later
+    // A builtin the embedding application provides, see `host::Host`.
+    HostFn(HostFn),
+    // `expr ? a.b.c`
+    HasAttr {
         expr: GcExpr<'gc>,
         attr_path: Vec<GcExpr<'gc>>,
     },
@@ -89,41 +142,418 @@ pub enum Expr<'gc> {
     },
     Let {
         // let is the only place where dynamic attributes are disallowed
-        bindings: Vec<(Vec<GcExpr<'gc>>, GcExpr<'gc>)>,
-        // inherited: HashMap<String, GcExpr<'gc>>,    // inherit always inherits from parent env
+        bindings: Vec<(Symbol, GcExpr<'gc>)>,
         body: GcExpr<'gc>,                          // let ...; in body
     },
 }
 
-impl<'gc> PartialEq for Expr<'gc> {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Expr::Int(s), Expr::Int(o)) => s == o,
-            _ => unimplemented!("cannot eq-compare {:?} with {:?}", self, other),
-        }
-    }
-}
-
-impl<'gc> Eq for Expr<'gc> {}
-
 impl<'gc> Expr<'gc> {
     /// Values cannot be evaluated any further. This matters when we force
     /// arguments e.g. for binary ops, but also for the actual evaluation.
     pub fn is_value(&self) -> bool {
+        matches!(
+            self,
+            Expr::Null()
+                | Expr::Int(_)
+                | Expr::Float(_)
+                | Expr::Bool(_)
+                | Expr::String(_)
+                | Expr::Path(_)
+                | Expr::List(_)
+                | Expr::AttrSet(_)
+                | Expr::Closure { .. }
+                | Expr::Pap { .. }
+                | Expr::PrimOp { .. }
+                | Expr::HostFn(_)
+        )
+    }
+
+    /// Closed expressions don't depend on the env they are evaluated in,
+    /// so they can be passed around as they are.
+    pub fn is_closed(&self) -> bool {
+        self.is_value() || matches!(self, Expr::Thunk { .. })
+    }
+
+    /// Attribute names that are known at parse time, i.e. `a`, `"a"` but not
+    /// `${a}` or `"${a}"`.
+    pub fn attr_name(&self) -> Option<String> {
         match self {
-            Expr::Null() => true,
-            Expr::Int(_) => true,
-            Expr::Float(_) => true,
-            Expr::Bool(_) => true,
-            Expr::String(_) => true,
-            Expr::InterpolatedString(_) => true,
-            Expr::Path(_) => true,
-            Expr::List(_) => true,
-            Expr::Attrs  { .. } => true,
-            Expr::Pap { .. } => true,
-            _ => false,
+            Expr::String(s) => Some(s.clone()),
+            Expr::InterpolatedString(parts) => {
+                let mut name = String::new();
+                for part in parts {
+                    match **part {
+                        Expr::String(ref s) => name.push_str(s),
+                        _ => return None,
+                    }
+                }
+                Some(name)
+            }
+            _ => None,
         }
     }
+
+    /// How nix refers to the type of a value in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Expr::Null() => "null",
+            Expr::Int(_) => "an integer",
+            Expr::Float(_) => "a float",
+            Expr::Bool(_) => "a Boolean",
+            Expr::String(_) | Expr::InterpolatedString(_) => "a string",
+            Expr::Path(_) => "a path",
+            Expr::List(_) | Expr::ListLiteral(_) => "a list",
+            Expr::AttrSet(_) | Expr::Attrs { .. } => "a set",
+            Expr::Lambda { .. } | Expr::Closure { .. } => "a function",
+            Expr::PrimOp { .. } | Expr::HostFn(_) => "a built-in function",
+            Expr::Pap { .. } => "a partially applied built-in function",
+            _ => "a thunk",
+        }
+    }
+}
+
+/// Print values the way `nix-instantiate --eval` does. Anything that isn't
+/// evaluated yet is `<CODE>`.
+impl<'gc> fmt::Display for Expr<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Null() => write!(f, "null"),
+            Expr::Int(i) => write!(f, "{}", i),
+            Expr::Float(x) => write!(f, "{}", format_float(*x)),
+            Expr::Bool(b) => write!(f, "{}", b),
+            Expr::String(s) => write_string(f, s),
+            Expr::Path(p) => write!(f, "{}", p),
+            Expr::List(elems) => {
+                write!(f, "[ ")?;
+                for elem in elems {
+                    write!(f, "{} ", **elem)?;
+                }
+                write!(f, "]")
+            }
+            Expr::AttrSet(attrs) => {
+                write!(f, "{{ ")?;
+                for (name, value) in attrs.iter() {
+                    if is_identifier(name) {
+                        write!(f, "{}", name)?;
+                    } else {
+                        write_string(f, name)?;
+                    }
+                    write!(f, " = {}; ", **value)?;
+                }
+                write!(f, "}}")
+            }
+            Expr::Thunk { t } => match *t.read() {
+                ThunkState::Value(value) => write!(f, "{}", *value),
+                _ => write!(f, "<CODE>"),
+            },
+            Expr::Closure { .. } => write!(f, "<LAMBDA>"),
+            Expr::PrimOp { .. } | Expr::HostFn(_) => write!(f, "<PRIMOP>"),
+            Expr::Pap { .. } => write!(f, "<PRIMOP-APP>"),
+            _ => write!(f, "<CODE>"),
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Whether `name` can be printed as an attribute name without quotes.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'' || c == '-')
+        }
+        _ => false,
+    }
+}
+
+/// Floats print like C's `%g`, i.e. with six significant digits, switching
+/// to scientific notation for very large or small numbers:
+///
+/// nix-repl> [ 1.5 100000.0 1000000.0 0.0001 0.00001 ]
+/// [ 1.5 100000 1e+06 0.0001 1e-05 ]
+pub fn format_float(x: f64) -> String {
+    if x.is_nan() {
+        return if x.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if x.is_infinite() {
+        return if x < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    // rounding to six digits first gives us the exponent %g decides on
+    let scientific = format!("{:.5e}", x);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    if !(-4..6).contains(&exponent) {
+        format!(
+            "{}e{}{:02}",
+            trim_zeros(mantissa),
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    } else {
+        trim_zeros(&format!("{:.*}", (5 - exponent) as usize, x)).to_string()
+    }
+}
+
+/// `toString` doesn't use `format_float` but C's `%f`, i.e. always six
+/// decimals and never scientific notation:
+///
+/// nix-repl> toString 1.5
+/// "1.500000"
+pub fn float_to_string(x: f64) -> String {
+    if x.is_finite() {
+        format!("{:.6}", x)
+    } else {
+        format_float(x)
+    }
+}
+
+fn trim_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// An evaluated attribute set. All values are closed, see `Expr::is_closed`.
+///
+/// The attributes are kept in a persistent AVL tree whose nodes are shared
+/// between sets, so that `//` (see `update`) doesn't have to copy them.
+/// The nodes are garbage collected like everything else, which also means
+/// each is traced once, no matter how many sets share it.
+#[derive(Clone, Default, Collect)]
+#[collect(no_drop)]
+pub struct AttrSet<'gc> {
+    root: Tree<'gc>,
+    len: usize,
+}
+
+type Tree<'gc> = Option<Gc<'gc, Node<'gc>>>;
+
+/// Nodes are never changed once they're made, inserting copies the path
+/// from the root down to where the attribute goes.
+#[derive(Collect)]
+#[collect(no_drop)]
+struct Node<'gc> {
+    name: String,
+    value: GcExpr<'gc>,
+    left: Tree<'gc>,
+    right: Tree<'gc>,
+    height: usize,
+}
+
+fn height(tree: &Tree) -> usize {
+    tree.as_ref().map_or(0, |node| node.height)
+}
+
+fn node<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: String,
+    value: GcExpr<'gc>,
+    left: Tree<'gc>,
+    right: Tree<'gc>,
+) -> Gc<'gc, Node<'gc>> {
+    let height = 1 + height(&left).max(height(&right));
+    Gc::allocate(
+        mc,
+        Node {
+            name,
+            value,
+            left,
+            right,
+            height,
+        },
+    )
+}
+
+/// `node`, rotating if one side got two higher than the other.
+fn balance<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: String,
+    value: GcExpr<'gc>,
+    left: Tree<'gc>,
+    right: Tree<'gc>,
+) -> Gc<'gc, Node<'gc>> {
+    if height(&left) > height(&right) + 1 {
+        let l = left.unwrap();
+        if height(&l.left) >= height(&l.right) {
+            let right = node(mc, name, value, l.right, right);
+            node(mc, l.name.clone(), l.value, l.left, Some(right))
+        } else {
+            let lr = l.right.unwrap();
+            let left = node(mc, l.name.clone(), l.value, l.left, lr.left);
+            let right = node(mc, name, value, lr.right, right);
+            node(mc, lr.name.clone(), lr.value, Some(left), Some(right))
+        }
+    } else if height(&right) > height(&left) + 1 {
+        let r = right.unwrap();
+        if height(&r.right) >= height(&r.left) {
+            let left = node(mc, name, value, left, r.left);
+            node(mc, r.name.clone(), r.value, Some(left), r.right)
+        } else {
+            let rl = r.left.unwrap();
+            let left = node(mc, name, value, left, rl.left);
+            let right = node(mc, r.name.clone(), r.value, rl.right, r.right);
+            node(mc, rl.name.clone(), rl.value, Some(left), Some(right))
+        }
+    } else {
+        node(mc, name, value, left, right)
+    }
+}
+
+/// `tree` with `name` set to `value`, unless it's there already and not
+/// `replace`. `added` says whether `name` is new.
+fn insert<'gc>(
+    mc: MutationContext<'gc, '_>,
+    tree: Tree<'gc>,
+    name: String,
+    value: GcExpr<'gc>,
+    replace: bool,
+    added: &mut bool,
+) -> Gc<'gc, Node<'gc>> {
+    let n = match tree {
+        Some(n) => n,
+        None => {
+            *added = true;
+            return node(mc, name, value, None, None);
+        }
+    };
+    match name.cmp(&n.name) {
+        Ordering::Less => {
+            let left = insert(mc, n.left, name, value, replace, added);
+            balance(mc, n.name.clone(), n.value, Some(left), n.right)
+        }
+        Ordering::Greater => {
+            let right = insert(mc, n.right, name, value, replace, added);
+            balance(mc, n.name.clone(), n.value, n.left, Some(right))
+        }
+        Ordering::Equal if replace => node(mc, name, value, n.left, n.right),
+        Ordering::Equal => n,
+    }
+}
+
+impl<'gc> AttrSet<'gc> {
+    pub fn new() -> AttrSet<'gc> {
+        AttrSet::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<GcExpr<'gc>> {
+        let mut tree = self.root;
+        while let Some(n) = tree {
+            tree = match name.cmp(&n.name) {
+                Ordering::Less => n.left,
+                Ordering::Greater => n.right,
+                Ordering::Equal => return Some(n.value),
+            };
+        }
+        None
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn insert(&mut self, mc: MutationContext<'gc, '_>, name: String, value: GcExpr<'gc>) {
+        self.insert_with(mc, name, value, true);
+    }
+
+    fn insert_with(&mut self, mc: MutationContext<'gc, '_>, name: String, value: GcExpr<'gc>, replace: bool) {
+        let mut added = false;
+        self.root = Some(insert(mc, self.root, name, value, replace, &mut added));
+        if added {
+            self.len += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// `self // other`, i.e. `other` wins. The smaller side's attributes
+    /// are inserted into the bigger one, which shares everything else with
+    /// the result, so putting a few attributes on top of a big set doesn't
+    /// depend on its size beyond a logarithm.
+    pub fn update(&self, mc: MutationContext<'gc, '_>, other: &AttrSet<'gc>) -> AttrSet<'gc> {
+        let (mut result, smaller, replace) = if self.len >= other.len {
+            (self.clone(), other, true)
+        } else {
+            (other.clone(), self, false)
+        };
+        for (name, value) in smaller.iter() {
+            result.insert_with(mc, name.clone(), *value, replace);
+        }
+        result
+    }
+
+    /// Iterate in nix order, i.e. sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &GcExpr<'gc>)> {
+        let mut iter = Iter { stack: Vec::new() };
+        iter.push_left(&self.root);
+        iter
+    }
+}
+
+impl<'gc> fmt::Debug for AttrSet<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// In-order traversal of a `Tree`, `stack` holding the nodes whose left
+/// side is done.
+struct Iter<'a, 'gc> {
+    stack: Vec<&'a Node<'gc>>,
+}
+
+impl<'a, 'gc> Iter<'a, 'gc> {
+    fn push_left(&mut self, mut tree: &'a Tree<'gc>) {
+        while let Some(n) = tree {
+            self.stack.push(n);
+            tree = &n.left;
+        }
+    }
+}
+
+impl<'a, 'gc> Iterator for Iter<'a, 'gc> {
+    type Item = (&'a String, &'a GcExpr<'gc>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.stack.pop()?;
+        self.push_left(&n.right);
+        Some((&n.name, &n.value))
+    }
+}
+
+/// The state behind an `Expr::Thunk`. Thunks are shared (e.g. a `let`
+/// binding used twice) so after the first force we overwrite the suspended
+/// computation with its value, see `Cont::UpdateCont`.
+#[derive(Debug, Clone, Collect)]
+#[collect(no_drop)]
+pub enum ThunkState<'gc> {
+    Suspended { expr: GcExpr<'gc>, env: GcEnv<'gc> },
+    // Being evaluated. Running into it again means it depends on itself,
+    // e.g. `let x = x; in x`. If evaluating it fails it goes back to being
+    // `Suspended`, so forcing it again fails the same way.
+    Blackhole { expr: GcExpr<'gc>, env: GcEnv<'gc> },
+    Value(GcExpr<'gc>),
 }
 
 #[derive(Debug, Clone, Collect)]
@@ -131,6 +561,48 @@ impl<'gc> Expr<'gc> {
 pub struct Env<'gc> {
     up: Option<Gc<'gc, Env<'gc>>>,
     values: HashMap<String, GcExpr<'gc>>,
+    // The (closed) set expression of a `with` scope. These don't have values
+    // of their own, see `Env::new_with`.
+    with: Option<GcExpr<'gc>>,
+}
+
+/// A step of comparing values, see `Cont::CompareCont`. The ones working
+/// through lists look at one pair of elements at a time, `next`, so each
+/// element is only visited once. The lists are `Expr::List`s, so that the
+/// continuation is cheap to copy.
+#[derive(Debug, Clone, Collect)]
+#[collect(no_drop)]
+pub enum Comparison<'gc> {
+    Equal(GcExpr<'gc>, GcExpr<'gc>),
+    LessThan(GcExpr<'gc>, GcExpr<'gc>),
+    // Pairwise `==` of the elements of two lists, or the values of two sets.
+    AllEqual {
+        left: GcExpr<'gc>,
+        right: GcExpr<'gc>,
+        next: usize,
+    },
+    // `<` of two lists: the first pair of elements that isn't equal decides.
+    Lexicographic {
+        left: GcExpr<'gc>,
+        right: GcExpr<'gc>,
+        next: usize,
+    },
+    // `builtins.elem x list`
+    Elem {
+        x: GcExpr<'gc>,
+        list: GcExpr<'gc>,
+        next: usize,
+    },
+}
+
+/// What `sort` has merged so far in its current pass, last element first.
+/// Adding to it doesn't touch what's there already, so `Cont::SortCont`s
+/// are cheap to copy and the collector doesn't trace the rest again.
+#[derive(Debug, Collect)]
+#[collect(no_drop)]
+pub struct Merged<'gc> {
+    pub elem: GcExpr<'gc>,
+    pub rest: Option<Gc<'gc, Merged<'gc>>>,
 }
 
 #[derive(Debug, Clone, Collect)]
@@ -141,10 +613,128 @@ pub enum Cont<'gc> {
         env: GcEnv<'gc>,
         arity: usize,
     },
+    // Waiting for the argument of a `{ a, b }: ...` lambda to become a set
+    // so we can destructure it.
+    FormalsCont {
+        lambda: GcExpr<'gc>,
+        env: GcEnv<'gc>, // the env the lambda was defined in
+        arg: GcExpr<'gc>,
+    },
     ForceAppCont {
-        f: GcExpr<'gc>, /// lambda to apply to after all args have been forced
+        f: GcExpr<'gc>, // lambda to apply to after all args have been forced
         unforced_args: Vec<GcExpr<'gc>>, // we consume unforced and append to forced args.
         forced_args: Vec<GcExpr<'gc>>,
+        env: GcEnv<'gc>, // the unforced args still need evaluating in here
+    },
+    // Pushed when we start forcing a thunk. Once the thunk's expression has
+    // been reduced to a value we pop this and overwrite the thunk.
+    UpdateCont {
+        t: GcThunk<'gc>,
+    },
+    // Building an attribute set, waiting for the key of `attrs`'s
+    // `index`th dynamic attribute. `set` holds what we have so far.
+    DynamicAttrsCont {
+        attrs: GcExpr<'gc>,
+        index: usize,
+        set: GcCell<'gc, AttrSet<'gc>>,
+        env: GcEnv<'gc>,
+    },
+    // Waiting for the condition of an `if`.
+    IfCont {
+        then_expr: GcExpr<'gc>,
+        else_expr: GcExpr<'gc>,
+        env: GcEnv<'gc>,
+    },
+    // Waiting for the condition of an `assert` at `pos`.
+    AssertCont {
+        body: GcExpr<'gc>,
+        env: GcEnv<'gc>,
+        pos: Pos,
+    },
+    // Waiting for the left side of `&&`, `||` or `->`, which decides whether
+    // we need to look at the right side at all.
+    BoolOpCont {
+        op: &'gc str,
+        right: GcExpr<'gc>,
+        env: GcEnv<'gc>,
+    },
+    // Checks the value is a boolean, and negates it for `!`.
+    BoolCont {
+        negate: bool,
+    },
+    // A primop needed a value inside one of its arguments forced, e.g. an
+    // element when comparing lists. Once that's done it starts over.
+    RetryCont {
+        name: &'gc str,
+        args: Vec<GcExpr<'gc>>,
+    },
+    // Comparing with `name`, e.g. two lists with `==`. `todo` holds the
+    // comparisons we're in the middle of, innermost last, see
+    // `eval::compare_values`. We're waiting for a value inside of them.
+    CompareCont {
+        name: &'gc str,
+        todo: Vec<Comparison<'gc>>,
+        env: GcEnv<'gc>,
+    },
+    // `sort f list` is a bottom-up merge sort. Each pass merges neighbouring
+    // runs of `width` elements of the list `items` into `merged`, the current
+    // pair starting at `start`. We're waiting for `f items[right] items[left]`,
+    // i.e. whether the right element goes first.
+    SortCont {
+        f: GcExpr<'gc>,
+        items: GcExpr<'gc>,
+        merged: Option<Gc<'gc, Merged<'gc>>>,
+        width: usize,
+        start: usize,
+        left: usize,
+        right: usize,
+        env: GcEnv<'gc>,
+    },
+    // Turning `parts` into one string, `done` is what we have up to
+    // `parts[next]`, which is being evaluated. `more` is for `toString`,
+    // which also accepts numbers, null, booleans and lists, but doesn't copy
+    // paths to the store.
+    CoerceCont {
+        parts: Vec<GcExpr<'gc>>,
+        next: usize,
+        done: String,
+        env: GcEnv<'gc>,
+        more: bool,
+    },
+    // Waiting for the set to select `attr_path` from, `env` is where the
+    // select expression is. With a `default` (from `or`) missing attributes
+    // aren't an error.
+    SelectCont {
+        attr_path: Vec<GcExpr<'gc>>,
+        default: Option<GcExpr<'gc>>,
+        env: GcEnv<'gc>,
+        pos: Pos,
+    },
+    // Waiting for the name of a dynamic attribute, i.e. the first element of
+    // `attr_path`, to select from `set`.
+    SelectKeyCont {
+        set: GcExpr<'gc>,
+        attr_path: Vec<GcExpr<'gc>>,
+        default: Option<GcExpr<'gc>>,
+        env: GcEnv<'gc>,
+        pos: Pos,
+    },
+    // Looking up `name` in the set of the `with` scope `scope`, because it
+    // isn't bound lexically. `pos` is where the variable is.
+    WithCont {
+        name: Symbol,
+        pos: Pos,
+        scope: GcEnv<'gc>,
+    },
+    // Same as `SelectCont` and `SelectKeyCont`, but for `set ? attr_path`.
+    HasAttrCont {
+        attr_path: Vec<GcExpr<'gc>>,
+        env: GcEnv<'gc>,
+    },
+    HasAttrKeyCont {
+        set: GcExpr<'gc>,
+        attr_path: Vec<GcExpr<'gc>>,
+        env: GcEnv<'gc>,
     },
 }
 
@@ -153,6 +743,137 @@ impl<'gc> Env<'gc> {
         Env {
             up: None,
             values: HashMap::new(),
+            with: None,
+        }
+    }
+
+    pub fn new(up: Option<GcEnv<'gc>>, values: HashMap<String, GcExpr<'gc>>) -> Env<'gc> {
+        Env { up, values, with: None }
+    }
+
+    /// The scope for the body of `with set; body`. Names bound lexically
+    /// always win over the ones in `set`, even if they are bound further
+    /// out:
+    ///
+    /// nix-repl> let x = 1; in with { x = 2; }; x
+    /// 1
+    ///
+    /// so `lookup` skips these scopes and `find_with` is only used once that
+    /// failed. `set` should be closed, it is only forced if needed.
+    pub fn new_with(up: GcEnv<'gc>, set: GcExpr<'gc>) -> Env<'gc> {
+        Env {
+            up: Some(up),
+            values: HashMap::new(),
+            with: Some(set),
+        }
+    }
+
+    pub fn up(&self) -> Option<GcEnv<'gc>> {
+        self.up
+    }
+
+    /// The innermost `with` scope, starting at `env` itself, together with
+    /// its set.
+    pub fn find_with(mut env: GcEnv<'gc>) -> Option<(GcEnv<'gc>, GcExpr<'gc>)> {
+        loop {
+            if let Some(set) = env.with {
+                return Some((env, set));
+            }
+            env = env.up?;
+        }
+    }
+
+    /// Create a recursive scope below `up`, i.e. every binding can see every
+    /// other binding (and itself). Used by `let`:
+    ///
+    /// nix-repl> let a = b; b = 10; in a
+    /// 10
+    ///
+    /// Each binding becomes a thunk closing over the new scope. Because `Gc`
+    /// is immutable we can't allocate the scope and its thunks in one go, so
+    /// the thunks start out pointing at `up` and get patched once the scope
+    /// exists.
+    pub fn new_rec(
+        mc: MutationContext<'gc, '_>,
+        up: GcEnv<'gc>,
+        bindings: Vec<(String, GcExpr<'gc>)>,
+    ) -> GcEnv<'gc> {
+        let (values, thunks) = Env::close_bindings(mc, up, bindings);
+        let env = Gc::allocate(
+            mc,
+            Env {
+                up: Some(up),
+                values,
+                with: None,
+            },
+        );
+        for t in thunks {
+            let expr = match *t.read() {
+                ThunkState::Suspended { expr, .. } => expr,
+                _ => unreachable!("fresh thunk already evaluated"),
+            };
+            *t.write(mc) = ThunkState::Suspended { expr, env };
+        }
+        env
+    }
+
+    /// Turn the right-hand sides of `bindings` into values or thunks over
+    /// `env`. The returned thunks are the ones a recursive scope has to patch
+    /// to point at itself; `inherit x;` is not among them as it always looks
+    /// outside. All names of one `inherit (from) ...;` share a thunk for
+    /// `from`.
+    pub fn close_bindings(
+        mc: MutationContext<'gc, '_>,
+        env: GcEnv<'gc>,
+        bindings: Vec<(String, GcExpr<'gc>)>,
+    ) -> (HashMap<String, GcExpr<'gc>>, Vec<GcThunk<'gc>>) {
+        let mut values = HashMap::with_capacity(bindings.len());
+        let mut thunks = Vec::with_capacity(bindings.len());
+        let mut froms: Vec<(GcExpr<'gc>, GcExpr<'gc>)> = Vec::new();
+        let thunk = |expr, thunks: &mut Vec<GcThunk<'gc>>| {
+            let t = GcCell::allocate(mc, ThunkState::Suspended { expr, env });
+            thunks.push(t);
+            Gc::allocate(mc, Expr::Thunk { t })
+        };
+        for (name, expr) in bindings {
+            let value = match *expr {
+                _ if expr.is_closed() => expr,
+                Expr::InheritedVar(..) => {
+                    let t = GcCell::allocate(mc, ThunkState::Suspended { expr, env });
+                    Gc::allocate(mc, Expr::Thunk { t })
+                }
+                Expr::InheritFrom { from, ref name, pos } => {
+                    let shared = match froms.iter().find(|(f, _)| Gc::ptr_eq(*f, from)) {
+                        Some(&(_, shared)) => shared,
+                        None if from.is_closed() => from,
+                        None => {
+                            let shared = thunk(from, &mut thunks);
+                            froms.push((from, shared));
+                            shared
+                        }
+                    };
+                    let select = Expr::InheritFrom {
+                        from: shared,
+                        name: name.clone(),
+                        pos,
+                    };
+                    thunk(Gc::allocate(mc, select), &mut thunks)
+                }
+                _ => thunk(expr, &mut thunks),
+            };
+            values.insert(name, value);
+        }
+        (values, thunks)
+    }
+
+    /// Find `name` in this scope or any of the enclosing ones.
+    pub fn lookup(&self, name: &str) -> Option<GcExpr<'gc>> {
+        let mut env = self;
+        loop {
+            if let Some(value) = env.values.get(name) {
+                return Some(*value);
+            }
+            env = env.up.as_ref()?;
         }
     }
 }
@@ -160,13 +881,73 @@ impl<'gc> Env<'gc> {
 pub type GcExpr<'gc> = Gc<'gc, Expr<'gc>>;
 pub type GcEnv<'gc> = Gc<'gc, Env<'gc>>;
 pub type GcStack<'gc> = GcCell<'gc, Vec<Cont<'gc>>>;
+pub type GcThunk<'gc> = GcCell<'gc, ThunkState<'gc>>;
 
+/// Everything the evaluator needs to carry on, and so everything the garbage
+/// collector has to keep between two slices of evaluation.
 #[derive(Debug, Copy, Clone, Collect)]
 #[collect(no_drop)]
 pub struct ExprRoot<'gc> {
-    pub root: GcExpr<'gc>,
-    pub env: GcEnv<'gc>,
+    // The expression being evaluated and its env, both change every step.
+    pub root: GcCell<'gc, GcExpr<'gc>>,
+    pub env: GcCell<'gc, GcEnv<'gc>>,
     pub stack: GcStack<'gc>,
 }
 
+impl<'gc> ExprRoot<'gc> {
+    pub fn new(mc: MutationContext<'gc, '_>, expr: GcExpr<'gc>, env: GcEnv<'gc>) -> ExprRoot<'gc> {
+        ExprRoot {
+            root: GcCell::allocate(mc, expr),
+            env: GcCell::allocate(mc, env),
+            stack: GcCell::allocate(mc, Vec::new()),
+        }
+    }
+}
+
 make_arena!(pub ExprArena, ExprRoot);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gc_arena::rootless_arena;
+
+    #[test]
+    fn check_attr_set_update() {
+        rootless_arena(|mc| {
+            let int = |i: i64| Gc::allocate(mc, Expr::Int(i));
+            let value = |set: &AttrSet, name: &str| match set.get(name).as_deref() {
+                Some(Expr::Int(i)) => Some(*i),
+                _ => None,
+            };
+            // scrambled, so the tree has to rebalance both ways
+            let names: Vec<String> = (0..500).map(|i| format!("a{}", (i * 7919) % 1000)).collect();
+            let mut expected = std::collections::BTreeMap::new();
+            let mut set = AttrSet::new();
+            for (i, name) in names.iter().enumerate() {
+                set.insert(mc, name.clone(), int(i as i64));
+                expected.insert(name.clone(), i as i64);
+            }
+            set.insert(mc, names[0].clone(), int(-1));
+            expected.insert(names[0].clone(), -1);
+            assert_eq!(set.len(), expected.len());
+            let actual: Vec<_> = set.iter().map(|(name, _)| name.clone()).collect();
+            assert_eq!(actual, expected.keys().cloned().collect::<Vec<_>>());
+            assert!(expected.iter().all(|(name, i)| value(&set, name) == Some(*i)));
+
+            let mut small = AttrSet::new();
+            small.insert(mc, names[1].clone(), int(1000));
+            small.insert(mc, "new".to_string(), int(1001));
+            let updated = set.update(mc, &small);
+            assert_eq!(updated.len(), set.len() + 1);
+            assert_eq!(value(&updated, &names[1]), Some(1000));
+            assert_eq!(value(&updated, &names[2]), Some(2));
+            // the other way around the bigger side's attributes win
+            assert_eq!(value(&small.update(mc, &set), &names[1]), Some(1));
+            assert_eq!(value(&small.update(mc, &set), "new"), Some(1001));
+            // neither side changes
+            assert_eq!(value(&set, &names[1]), Some(1));
+            assert_eq!(value(&set, "new"), None);
+            assert_eq!(small.len(), 2);
+        });
+    }
+}
@@ -0,0 +1,252 @@
+//! Embedding the evaluator in an application: handing it Rust values and
+//! functions, see `Host`, and calling nix functions with Rust values, see
+//! `eval::call`.
+use crate::eval::ErrorKind;
+use crate::expr::{AttrSet, Expr, GcExpr};
+use gc_arena::{Collect, Gc, MutationContext};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+
+/// A nix value built on the Rust side. Unlike `Expr` it doesn't live in an
+/// arena, so it can be made before there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Path(String),
+    List(Vec<Value>),
+    AttrSet(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn to_expr<'gc>(&self, mc: MutationContext<'gc, '_>) -> GcExpr<'gc> {
+        let expr = match self {
+            Value::Null => Expr::Null(),
+            Value::Bool(b) => Expr::Bool(*b),
+            Value::Int(i) => Expr::Int(*i),
+            Value::Float(x) => Expr::Float(*x),
+            Value::String(s) => Expr::String(s.clone()),
+            Value::Path(p) => Expr::Path(p.clone()),
+            Value::List(elems) => Expr::List(elems.iter().map(|e| e.to_expr(mc)).collect()),
+            Value::AttrSet(attrs) => {
+                let mut set = AttrSet::new();
+                for (name, value) in attrs {
+                    set.insert(mc, name.clone(), value.to_expr(mc));
+                }
+                Expr::AttrSet(set)
+            }
+        };
+        Gc::allocate(mc, expr)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(i: i32) -> Value {
+        Value::Int(i.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Value {
+        Value::Float(x)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+/// `None` is `null`.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(elems: Vec<T>) -> Value {
+        Value::List(elems.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<BTreeMap<String, T>> for Value {
+    fn from(attrs: BTreeMap<String, T>) -> Value {
+        Value::AttrSet(attrs.into_iter().map(|(name, value)| (name, value.into())).collect())
+    }
+}
+
+impl<T: Into<Value>> From<HashMap<String, T>> for Value {
+    fn from(attrs: HashMap<String, T>) -> Value {
+        Value::AttrSet(attrs.into_iter().map(|(name, value)| (name, value.into())).collect())
+    }
+}
+
+/// What a host function does with its arguments.
+pub type HostFnBody = dyn for<'gc> Fn(MutationContext<'gc, '_>, &[GcExpr<'gc>]) -> Result<GcExpr<'gc>, ErrorKind>;
+
+/// A function the application provides, which nix code calls like any
+/// other builtin.
+#[derive(Clone, Collect)]
+#[collect(require_static)]
+pub struct HostFn {
+    pub name: String,
+    pub arity: usize,
+    f: Rc<HostFnBody>,
+}
+
+impl HostFn {
+    /// `args` are evaluated, but only as far as a primop's are, i.e. the
+    /// elements of a list may still be thunks.
+    pub fn call<'gc>(&self, mc: MutationContext<'gc, '_>, args: &[GcExpr<'gc>]) -> Result<GcExpr<'gc>, ErrorKind> {
+        (self.f)(mc, args)
+    }
+}
+
+impl fmt::Debug for HostFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostFn({}, {})", self.name, self.arity)
+    }
+}
+
+/// What an application adds to the scope nix code is evaluated in, e.g.
+///
+/// ```ignore
+/// let mut host = Host::new();
+/// host.value("port", 8080).function("double", 1, |mc, args| match *args[0] {
+///     Expr::Int(i) => Ok(Gc::allocate(mc, Expr::Int(2 * i))),
+///     _ => Err(ErrorKind::Throw { message: "not a number".to_string() }),
+/// });
+/// let mut evaluation = Evaluation::with_host(&host, |mc| parse(mc, "double port"));
+/// ```
+#[derive(Clone, Default)]
+pub struct Host {
+    pub(crate) values: Vec<(String, Value)>,
+    pub(crate) functions: Vec<HostFn>,
+}
+
+impl Host {
+    pub fn new() -> Host {
+        Host::default()
+    }
+
+    /// Make `value` available as the global variable `name`.
+    pub fn value(&mut self, name: &str, value: impl Into<Value>) -> &mut Host {
+        self.values.push((name.to_string(), value.into()));
+        self
+    }
+
+    /// Make `f` available as `builtins.${name}` and as the global `name`.
+    /// It's called once it has `arity` arguments, see `HostFn::call`.
+    pub fn function(
+        &mut self,
+        name: &str,
+        arity: usize,
+        f: impl for<'gc> Fn(MutationContext<'gc, '_>, &[GcExpr<'gc>]) -> Result<GcExpr<'gc>, ErrorKind> + 'static,
+    ) -> &mut Host {
+        assert!(arity > 0, "host function '{}' needs to take arguments", name);
+        self.functions.push(HostFn {
+            name: name.to_string(),
+            arity,
+            f: Rc::new(f),
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{call, eval, Evaluation, Progress};
+    use crate::expr_parser::exprParser;
+    use crate::lexer::nix_lexer::Lexer;
+    use gc_arena::rootless_arena;
+
+    #[test]
+    fn check_host() {
+        let mut host = Host::new();
+        let mut settings = BTreeMap::new();
+        settings.insert("name".to_string(), Value::from("web"));
+        settings.insert("replicas".to_string(), Value::from(vec![1, 2]));
+        host.value("port", 8080)
+            .value("settings", settings)
+            .value("missing", None::<i64>)
+            .function("double", 1, |mc, args| match *args[0] {
+                Expr::Int(i) => Ok(Gc::allocate(mc, Expr::Int(2 * i))),
+                ref e => Err(ErrorKind::TypeError {
+                    expected: "an integer",
+                    got: e.type_name(),
+                }),
+            });
+        let evaluate = |src: &str| {
+            let mut evaluation = Evaluation::with_host(&host, |mc| {
+                exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap()
+            });
+            evaluation.run(1000, |_, result| result.map(|value| value.to_string()).map_err(|e| e.kind))
+        };
+        assert_eq!(evaluate("double (port + 1)"), Ok("16162".to_string()));
+        assert_eq!(evaluate("builtins.elem 4 (map builtins.double settings.replicas)"), Ok("true".to_string()));
+        assert_eq!(evaluate("settings"), Ok("{ name = \"web\"; replicas = [ 1 2 ]; }".to_string()));
+        assert_eq!(evaluate("missing"), Ok("null".to_string()));
+        assert_eq!(
+            evaluate("double \"x\""),
+            Err(ErrorKind::TypeError {
+                expected: "an integer",
+                got: "a string"
+            })
+        );
+
+        // calling nix functions from Rust
+        rootless_arena(|mc| {
+            let src = "{ a, b ? 1 }: x: a * b + x";
+            let f = eval(mc, exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap(), 100).unwrap();
+            let mut args = BTreeMap::new();
+            args.insert("a".to_string(), 6);
+            let result = call(mc, f, &[Value::from(args), Value::from(2)], 100).unwrap();
+            assert!(matches!(*result, Expr::Int(8)));
+        });
+        // a failing attribute fails the same way every time it's asked for
+        rootless_arena(|mc| {
+            let src = "let config = { port = throw \"no port\"; name = \"web\"; }; in attr: config.${attr}";
+            let f = eval(mc, exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap(), 100).unwrap();
+            let get = |attr: &str| call(mc, f, &[Value::from(attr)], 100).map(|v| v.to_string()).map_err(|e| e.kind);
+            let no_port = Err(ErrorKind::Throw {
+                message: "no port".to_string(),
+            });
+            assert_eq!(get("port"), no_port);
+            assert_eq!(get("name"), Ok("\"web\"".to_string()));
+            assert_eq!(get("port"), no_port);
+        });
+        let src = "x: y: x + y";
+        let mut evaluation =
+            Evaluation::new(|mc| exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap());
+        assert!(!evaluation.call(&[Value::from(1)]));
+        assert_eq!(evaluation.resume(100), Ok(Progress::Done));
+        assert!(evaluation.call(&[Value::from("a"), Value::from("b")]));
+        assert_eq!(evaluation.resume(100), Ok(Progress::Done));
+        assert_eq!(evaluation.value(|_, value| value.to_string()), Some("\"ab\"".to_string()));
+    }
+}
@@ -1,18 +1,26 @@
-/// Unfortunately we can't get away with just using the lexer that ships with
-/// lalrpop because it really is just a lexer, but string interpolation in nix
-/// requires the lexer to switch between in-string and out-of-string scanning
-/// mode. On top of that the lexer needs to keep track on how deep the
-/// expression nesting is to make sure it's balanced.
+//! Unfortunately we can't get away with just using the lexer that ships with
+//! lalrpop because it really is just a lexer, but string interpolation in nix
+//! requires the lexer to switch between in-string and out-of-string scanning
+//! mode. On top of that the lexer needs to keep track on how deep the
+//! expression nesting is to make sure it's balanced.
 
+use crate::expr::Pos;
 use crate::lexer::nix_lexer::{Error, Lexer, Token};
 
+#[allow(clippy::all, unused_variables)]
 pub mod nix_lexer {
     include!(concat!(env!("OUT_DIR"), "/nix_lexer.rs"));
 }
 
-#[derive(Debug)]
+// The parser has a single error type for both the lexer and the grammar
+// actions, so the errors the parser detects itself live here as well.
+#[derive(Debug, PartialEq)]
 pub enum LexicalError {
     NotGood((usize, usize, usize, usize)),
+    // `{ a.b = 1; a.b = 2; }`, `path` is the full attribute path.
+    DuplicateAttr { path: String, pos: Pos, first: Pos },
+    // `let ${a} = 1; in ...`
+    DynamicAttrInLet { pos: Pos },
 }
 
 impl<'input> Iterator for Lexer<'input> {
@@ -21,8 +29,7 @@ impl<'input> Iterator for Lexer<'input> {
     fn next(&mut self) -> Option<Self::Item> {
         match self.yylex() {
             Ok(next_item) => {
-                let length = self.yylength();
-                let (lineno, s, _, e) = self.error_state();
+                let (lineno, _, _, e) = self.error_state();
                 let span = Ok((lineno, next_item, e));
                 Some(span)
             }
@@ -44,6 +51,9 @@ mod tests {
         let mut lexer = Lexer::new("some_id", Vec::with_capacity(10), 0);
         assert_eq!(lexer.yylex().unwrap(), Token::ID("some_id".to_string()));
 
+        let mut lexer = Lexer::new("foldl'", Vec::with_capacity(10), 0);
+        assert_eq!(lexer.yylex().unwrap(), Token::ID("foldl'".to_string()));
+
         let mut lexer = Lexer::new("//", Vec::with_capacity(10), 0);
         assert_eq!(lexer.yylex().unwrap(), Token::UPDATE);
 
@@ -57,7 +67,7 @@ mod tests {
         let mut lexer = Lexer::new(r#""xx-s\\-xx""#, Vec::with_capacity(10), 0);
         lexer.yylex().unwrap();
         assert_eq!(lexer.yylex().unwrap(), Token::STRING_PART(r"xx-s\\-xx".to_string()));
-        // TODO escape sequences, interpolation
+        // escapes are decoded by the parser, see `parser_prelude::unescape`
     }
 
     fn _collect(lexer: &mut Lexer, trace: bool) -> Vec<Token> {
@@ -71,7 +81,7 @@ mod tests {
                 Err(Error::Unmatch) => panic!("_collect failed at {:?}. So far: {:?}", lexer.error_state(), ret),
             }
         }
-        return ret;
+        ret
     }
 
     #[test]
@@ -147,9 +157,34 @@ mod tests {
 
     #[test]
     fn check_ad_hoc() {
-        let s = include_str!("lang-tests/eval-okay-builtins-add.nix");
         let mut lexer = Lexer::new("{ inherit pkgs; }", Vec::with_capacity(10), 0);
-        let vv = _collect(&mut lexer, true);
+        _collect(&mut lexer, true);
+    }
+
+    #[test]
+    fn check_line_numbers() {
+        let s = "a\nbb\n\n  c d\n# comment\ne /* x\n y */ f \"s\ns\" g\n";
+        let lines: Vec<(usize, Token)> = Lexer::new(s, Vec::with_capacity(10), 0)
+            .map(|t| {
+                let (l, t, _) = t.unwrap();
+                (l, t)
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (0, Token::ID("a".to_string())),
+                (1, Token::ID("bb".to_string())),
+                (3, Token::ID("c".to_string())),
+                (3, Token::ID("d".to_string())),
+                (5, Token::ID("e".to_string())),
+                (6, Token::ID("f".to_string())),
+                (6, Token::STRING_QUOTE),
+                (6, Token::STRING_PART("s\ns".to_string())),
+                (7, Token::STRING_QUOTE),
+                (7, Token::ID("g".to_string())),
+            ]
+        );
     }
 
     #[test]
@@ -164,7 +199,7 @@ mod tests {
                     println!("{:?}", path);
                     let s = std::fs::read_to_string(path).expect("could not read file");
                     let mut lexer = Lexer::new(&s, Vec::with_capacity(10), 0);
-                    let vv = _collect(&mut lexer, false);
+                    _collect(&mut lexer, false);
                 }
                 Err(e) => {
                     panic!("not a valid file: {:?}", e);
@@ -1,11 +1,21 @@
+// gc-arena-derive 0.2 emits its `Collect` impls inside a `const _` block.
+#![allow(non_local_definitions)]
+
 #[allow(unused_imports)]
 #[macro_use]
 extern crate lalrpop_util;
 
-lalrpop_mod!(pub expr_parser);
+lalrpop_mod!(
+    #[allow(clippy::all, unused_parens, unused_braces, unused_mut, unused_imports)]
+    pub expr_parser
+);
 
 pub mod expr;
 pub mod lexer;
 pub mod parser;
 pub mod eval;
+pub mod builtins;
+pub mod host;
+pub mod de;
+pub mod ser;
 mod parser_prelude;
\ No newline at end of file
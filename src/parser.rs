@@ -1,8 +1,10 @@
 #[cfg(test)]
 mod tests {
+    use crate::expr::{Expr, Pos};
     use crate::lexer::nix_lexer::Lexer;
+    use crate::lexer::LexicalError;
     use gc_arena::rootless_arena;
-    use crate::expr_parser::exprParser;
+    use lalrpop_util::ParseError;
 
     #[test]
     fn check_simple_expression() {
@@ -68,6 +70,81 @@ mod tests {
         });
     }
 
+    #[test]
+    fn parse_at_pattern() {
+        for &(src, ellipsis, n_formals) in &[
+            ("args@{ x, y }: x", false, 2),
+            ("args@{ x, y, }: x", false, 2),
+            ("args@{ x, ... }: x", true, 1),
+            ("args@{ ... }: x", true, 0),
+            ("args@{ }: x", false, 0),
+            ("{ x, y }@args: x", false, 2),
+            ("{ x, y, }@args: x", false, 2),
+            ("{ x, ... }@args: x", true, 1),
+            ("{ ... }@args: x", true, 0),
+            ("{ }@args: x", false, 0),
+        ] {
+            let lexer = Lexer::new(src, Vec::with_capacity(10), 0);
+            rootless_arena(|mc| match *crate::expr_parser::exprParser::new().parse(mc, lexer).unwrap() {
+                Expr::Lambda {
+                    arg: Some(ref arg),
+                    formals: Some((ref formals, e)),
+                    ..
+                } => {
+                    assert_eq!(arg, "args", "{}", src);
+                    assert_eq!(e, ellipsis, "{}", src);
+                    assert_eq!(formals.len(), n_formals, "{}", src);
+                }
+                ref e => panic!("{} parsed as {:?}", src, e),
+            });
+        }
+    }
+    #[test]
+    fn parse_fail_dup_attrs() {
+        for &(src, path, line, first) in &[
+            (include_str!("lang-tests/parse-fail-dup-attrs-1.nix"), "x", 3, 1),
+            (include_str!("lang-tests/parse-fail-dup-attrs-2.nix"), "x", 10, 9),
+            (include_str!("lang-tests/parse-fail-dup-attrs-3.nix"), "x", 10, 9),
+            (include_str!("lang-tests/parse-fail-dup-attrs-4.nix"), "services.ssh.port", 3, 2),
+            (include_str!("lang-tests/parse-fail-dup-attrs-7.nix"), "x", 7, 6),
+            (include_str!("lang-tests/parse-fail-mixed-nested-attrs1.nix"), "x.z", 3, 2),
+            (include_str!("lang-tests/parse-fail-mixed-nested-attrs2.nix"), "x.y", 3, 2),
+            // formals, including the @-name, can't repeat either
+            (include_str!("lang-tests/parse-fail-dup-formals.nix"), "x", 1, 1),
+            (include_str!("lang-tests/parse-fail-patterns-1.nix"), "args", 1, 1),
+            ("{ a,\n  b ? a }@b: a", "b", 2, 1),
+        ] {
+            let lexer = Lexer::new(src, Vec::with_capacity(10), 0);
+            rootless_arena(|mc| {
+                assert_eq!(
+                    crate::expr_parser::exprParser::new().parse(mc, lexer).unwrap_err(),
+                    ParseError::User {
+                        error: LexicalError::DuplicateAttr {
+                            path: path.to_string(),
+                            pos: Pos { line },
+                            first: Pos { line: first },
+                        }
+                    },
+                    "{}",
+                    src
+                )
+            });
+        }
+    }
+
+    #[test]
+    fn parse_fail_dynamic_let() {
+        let lexer = Lexer::new("let a = 1;\n  ${a} = 2; in a", Vec::with_capacity(10), 0);
+        rootless_arena(|mc| {
+            assert_eq!(
+                crate::expr_parser::exprParser::new().parse(mc, lexer).unwrap_err(),
+                ParseError::User {
+                    error: LexicalError::DynamicAttrInLet { pos: Pos { line: 2 } }
+                }
+            )
+        });
+    }
+
     #[test]
     fn smoke_test_parsing() {
         // lex all the files that we also expect to parse OK
@@ -0,0 +1,436 @@
+// Small helpers used by the actions in `expr_parser.lalrpop`. Keeping them
+// here means the grammar file stays readable.
+use crate::expr::{Expr, GcExpr, Pos, Symbol};
+use crate::lexer::nix_lexer::Token;
+use crate::lexer::LexicalError;
+use gc_arena::{Gc, MutationContext};
+use lalrpop_util::ParseError;
+
+type Error = ParseError<usize, Token, LexicalError>;
+
+/// Binary operators are plain primop applications, e.g. `1 + 2` becomes
+/// `App { f: PrimOp("+"), args: [1, 2] }`.
+pub fn binop<'gc>(
+    mc: MutationContext<'gc, '_>,
+    name: &'static str,
+    left: GcExpr<'gc>,
+    right: GcExpr<'gc>,
+) -> GcExpr<'gc> {
+    Gc::allocate(
+        mc,
+        Expr::App {
+            f: Gc::allocate(mc, Expr::PrimOp { name, arity: 2 }),
+            args: vec![left, right],
+            arity: 2,
+        },
+    )
+}
+
+/// `"..."`, which is only an `InterpolatedString` if there's actually
+/// something interpolated.
+pub fn string<'gc>(mc: MutationContext<'gc, '_>, parts: Vec<GcExpr<'gc>>) -> GcExpr<'gc> {
+    let mut s = String::new();
+    for part in &parts {
+        match **part {
+            Expr::String(ref part) => s.push_str(part),
+            _ => return Gc::allocate(mc, Expr::InterpolatedString(parts)),
+        }
+    }
+    Gc::allocate(mc, Expr::String(s))
+}
+
+/// Decode the escapes in a part of a `"..."` string. `\n`, `\r` and `\t`
+/// are what you'd expect, any other escaped character stands for itself.
+/// Line breaks in the source (`\r\n` or a lone `\r`) become `\n`.
+pub fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some('t') => result.push('\t'),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            },
+            '\r' => {
+                result.push('\n');
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// A piece of a `''...''` string before its indentation is stripped.
+pub enum IndentedPart<'gc> {
+    Text(String),
+    /// `''\x`, which doesn't count as indentation even if it's a space or
+    /// a newline.
+    Escaped(String),
+    Interpolation(GcExpr<'gc>),
+}
+
+/// `''...''`: a first line with nothing but spaces is dropped, and so is
+/// the smallest indentation of the lines that have anything but spaces on
+/// them. So is the last line if it's all spaces.
+pub fn indented_string<'gc>(mc: MutationContext<'gc, '_>, mut parts: Vec<IndentedPart<'gc>>) -> GcExpr<'gc> {
+    if let Some(IndentedPart::Text(first)) = parts.first_mut() {
+        let spaces = first.len() - first.trim_start_matches(' ').len();
+        if first[spaces..].starts_with('\n') {
+            first.drain(..=spaces);
+        }
+    }
+
+    let mut min_indent = usize::MAX;
+    let mut at_line_start = true;
+    let mut indent = 0;
+    for part in &parts {
+        match part {
+            IndentedPart::Text(s) => {
+                for c in s.chars() {
+                    match (at_line_start, c) {
+                        (true, ' ') => indent += 1,
+                        (_, '\n') => {
+                            at_line_start = true;
+                            indent = 0;
+                        }
+                        (true, _) => {
+                            at_line_start = false;
+                            min_indent = min_indent.min(indent);
+                        }
+                        (false, _) => {}
+                    }
+                }
+            }
+            // `''\n` and `${...}` are something, even right at the start
+            _ if at_line_start => {
+                at_line_start = false;
+                min_indent = min_indent.min(indent);
+            }
+            _ => {}
+        }
+    }
+
+    let n = parts.len();
+    let mut stripped_parts = Vec::with_capacity(n);
+    let mut at_line_start = true;
+    let mut dropped = 0;
+    for (i, part) in parts.into_iter().enumerate() {
+        let part = match part {
+            IndentedPart::Text(s) => {
+                let mut stripped = String::with_capacity(s.len());
+                for c in s.chars() {
+                    match (at_line_start, c) {
+                        (true, ' ') => {
+                            if dropped >= min_indent {
+                                stripped.push(c);
+                            }
+                            dropped += 1;
+                        }
+                        (true, '\n') => {
+                            dropped = 0;
+                            stripped.push(c);
+                        }
+                        (true, _) => {
+                            at_line_start = false;
+                            dropped = 0;
+                            stripped.push(c);
+                        }
+                        (false, _) => {
+                            at_line_start = c == '\n';
+                            stripped.push(c);
+                        }
+                    }
+                }
+                if i + 1 == n {
+                    if let Some(last_line) = stripped.rfind('\n') {
+                        if stripped[last_line + 1..].chars().all(|c| c == ' ') {
+                            stripped.truncate(last_line + 1);
+                        }
+                    }
+                }
+                Gc::allocate(mc, Expr::String(stripped))
+            }
+            IndentedPart::Escaped(s) => {
+                at_line_start = false;
+                dropped = 0;
+                Gc::allocate(mc, Expr::String(s))
+            }
+            IndentedPart::Interpolation(expr) => {
+                at_line_start = false;
+                dropped = 0;
+                expr
+            }
+        };
+        stripped_parts.push(part);
+    }
+    string(mc, stripped_parts)
+}
+
+/// `[ ... ]`, which can be a `List` straight away if all elements are
+/// values already, e.g. `[ 1 2 3 ]`.
+pub fn list_literal<'gc>(mc: MutationContext<'gc, '_>, elems: Vec<GcExpr<'gc>>) -> GcExpr<'gc> {
+    if elems.iter().all(|e| e.is_value()) {
+        Gc::allocate(mc, Expr::List(elems))
+    } else {
+        Gc::allocate(mc, Expr::ListLiteral(elems))
+    }
+}
+
+/// One `a.b.c = value;` (or inherited name) of a set or `let`, before
+/// nested paths get merged.
+pub struct Binding<'gc> {
+    pub attr_path: Vec<GcExpr<'gc>>,
+    pub value: GcExpr<'gc>,
+    pub pos: Pos,
+}
+
+/// `inherit a b;` and `inherit (from) a b;` become one binding per name, the
+/// latter all sharing the same `from` expression.
+pub fn inherit<'gc>(
+    mc: MutationContext<'gc, '_>,
+    from: Option<GcExpr<'gc>>,
+    names: Vec<(Symbol, Pos)>,
+) -> Vec<Binding<'gc>> {
+    names
+        .into_iter()
+        .map(|(name, pos)| {
+            let value = match from {
+                None => Expr::InheritedVar(name.clone(), pos),
+                Some(from) => Expr::InheritFrom {
+                    from,
+                    name: name.clone(),
+                    pos,
+                },
+            };
+            Binding {
+                attr_path: vec![Gc::allocate(mc, Expr::String(name))],
+                value: Gc::allocate(mc, value),
+                pos,
+            }
+        })
+        .collect()
+}
+
+/// Merge `bindings` into an `Expr::Attrs`, see `AttrsBuilder::add`.
+pub fn attrs<'gc>(
+    mc: MutationContext<'gc, '_>,
+    bindings: Vec<Binding<'gc>>,
+    recursive: bool,
+) -> Result<GcExpr<'gc>, Error> {
+    let mut builder = AttrsBuilder::new(recursive);
+    for binding in bindings {
+        builder.add(mc, &binding.attr_path, binding.value, binding.pos, "")?;
+    }
+    Ok(builder.build(mc))
+}
+
+/// Like `attrs`, but for `let` where all names have to be static.
+pub fn let_bindings<'gc>(
+    mc: MutationContext<'gc, '_>,
+    bindings: Vec<Binding<'gc>>,
+) -> Result<Vec<(Symbol, GcExpr<'gc>)>, Error> {
+    let mut builder = AttrsBuilder::new(true);
+    for binding in bindings {
+        builder.add(mc, &binding.attr_path, binding.value, binding.pos, "")?;
+    }
+    if let Some(&(_, _, pos)) = builder.dynamic.first() {
+        return Err(ParseError::User {
+            error: LexicalError::DynamicAttrInLet { pos },
+        });
+    }
+    Ok(builder
+        .attrs
+        .into_iter()
+        .map(|(name, node, _)| (name, node.build(mc)))
+        .collect())
+}
+
+/// `{ a, b ? 1 }: ...`, with `arg` for `args@{ ... }`. Like attributes, none
+/// of the names may appear twice.
+pub fn lambda<'gc>(
+    mc: MutationContext<'gc, '_>,
+    arg: Option<Symbol>,
+    formals: Vec<(GcExpr<'gc>, Pos)>,
+    ellipsis: bool,
+    body: GcExpr<'gc>,
+    pos: Pos,
+) -> Result<GcExpr<'gc>, Error> {
+    let mut seen: Vec<(&str, Pos)> = arg.iter().map(|arg| (arg.as_str(), pos)).collect();
+    for (formal, formal_pos) in &formals {
+        if let Expr::Formal(ref name, _) = **formal {
+            if let Some(&(_, first)) = seen.iter().find(|(seen, _)| seen == name) {
+                return Err(duplicate(name.clone(), *formal_pos, first));
+            }
+            seen.push((name, *formal_pos));
+        }
+    }
+    let formals = formals.into_iter().map(|(formal, _)| formal).collect();
+    Ok(Gc::allocate(
+        mc,
+        Expr::Lambda {
+            arg,
+            formals: Some((formals, ellipsis)),
+            body,
+            pos,
+        },
+    ))
+}
+
+fn duplicate(path: String, pos: Pos, first: Pos) -> Error {
+    ParseError::User {
+        error: LexicalError::DuplicateAttr { path, pos, first },
+    }
+}
+
+/// An `Expr::Attrs` under construction.
+struct AttrsBuilder<'gc> {
+    attrs: Vec<(Symbol, Node<'gc>, Pos)>,
+    dynamic: Vec<(GcExpr<'gc>, GcExpr<'gc>, Pos)>,
+    recursive: bool,
+}
+
+enum Node<'gc> {
+    Expr(GcExpr<'gc>),
+    Attrs(AttrsBuilder<'gc>),
+}
+
+impl<'gc> Node<'gc> {
+    fn build(self, mc: MutationContext<'gc, '_>) -> GcExpr<'gc> {
+        match self {
+            Node::Expr(expr) => expr,
+            Node::Attrs(builder) => builder.build(mc),
+        }
+    }
+}
+
+impl<'gc> AttrsBuilder<'gc> {
+    fn new(recursive: bool) -> AttrsBuilder<'gc> {
+        AttrsBuilder {
+            attrs: Vec::new(),
+            dynamic: Vec::new(),
+            recursive,
+        }
+    }
+
+    /// Nested paths can also extend sets written out in full, e.g.
+    /// `a = { b = 1; }; a.c = 2;`. Anything else (including inherited
+    /// names) can't be extended.
+    fn from_expr(expr: GcExpr<'gc>) -> Option<AttrsBuilder<'gc>> {
+        match *expr {
+            Expr::Attrs {
+                ref attrs,
+                ref dynamic,
+                recursive,
+            } => Some(AttrsBuilder {
+                attrs: attrs
+                    .iter()
+                    .map(|(name, value, pos)| (name.clone(), Node::Expr(*value), *pos))
+                    .collect(),
+                dynamic: dynamic.clone(),
+                recursive,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Add `attr_path = value;`, following the same rules as nix:
+    ///
+    /// - `a.b = 1; a.c = 2;` is `a = { b = 1; c = 2; };`
+    /// - `a = { b = 1; }; a.c = 2;` is fine too, and so is
+    ///   `a.c = 2; a = { b = 1; };`. Sets are only merged one level deep
+    ///   though, so the second `b` in `a.b.c = 1; a = { b.d = 2; };` is a
+    ///   duplicate.
+    /// - dynamic attributes, e.g. `${x}.b = 1;` are never merged as we don't
+    ///   know their names yet. Duplicates are an evaluation error instead.
+    ///
+    /// `prefix` is the path leading up to this set, for error messages.
+    fn add(
+        &mut self,
+        mc: MutationContext<'gc, '_>,
+        attr_path: &[GcExpr<'gc>],
+        value: GcExpr<'gc>,
+        pos: Pos,
+        prefix: &str,
+    ) -> Result<(), Error> {
+        let (first, rest) = attr_path.split_first().expect("empty attribute path");
+        let name = match first.attr_name() {
+            Some(name) => name,
+            None => {
+                let value = if rest.is_empty() {
+                    value
+                } else {
+                    let mut nested = AttrsBuilder::new(false);
+                    nested.add(mc, rest, value, pos, "")?;
+                    nested.build(mc)
+                };
+                self.dynamic.push((*first, value, pos));
+                return Ok(());
+            }
+        };
+        let path = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        let (node, first_pos) = match self.attrs.iter_mut().find(|(n, _, _)| *n == name) {
+            Some((_, node, first_pos)) => (node, *first_pos),
+            None => {
+                let node = if rest.is_empty() {
+                    Node::Expr(value)
+                } else {
+                    let mut nested = AttrsBuilder::new(false);
+                    nested.add(mc, rest, value, pos, &path)?;
+                    Node::Attrs(nested)
+                };
+                self.attrs.push((name, node, pos));
+                return Ok(());
+            }
+        };
+        if let Node::Expr(expr) = *node {
+            *node = Node::Attrs(AttrsBuilder::from_expr(expr).ok_or_else(|| duplicate(path.clone(), pos, first_pos))?);
+        }
+        let existing = match node {
+            Node::Attrs(existing) => existing,
+            Node::Expr(_) => unreachable!(),
+        };
+        if !rest.is_empty() {
+            return existing.add(mc, rest, value, pos, &path);
+        }
+        match *value {
+            Expr::Attrs {
+                ref attrs, ref dynamic, ..
+            } => {
+                for (name, value, pos) in attrs {
+                    if let Some((_, _, first_pos)) = existing.attrs.iter().find(|(n, _, _)| n == name) {
+                        return Err(duplicate(format!("{}.{}", path, name), *pos, *first_pos));
+                    }
+                    existing.attrs.push((name.clone(), Node::Expr(*value), *pos));
+                }
+                existing.dynamic.extend(dynamic.iter().cloned());
+                Ok(())
+            }
+            _ => Err(duplicate(path, pos, first_pos)),
+        }
+    }
+
+    fn build(self, mc: MutationContext<'gc, '_>) -> GcExpr<'gc> {
+        Gc::allocate(
+            mc,
+            Expr::Attrs {
+                attrs: self
+                    .attrs
+                    .into_iter()
+                    .map(|(name, node, pos)| (name, node.build(mc), pos))
+                    .collect(),
+                dynamic: self.dynamic,
+                recursive: self.recursive,
+            },
+        )
+    }
+}
@@ -0,0 +1,399 @@
+//! Turning Rust values into nix values with serde, e.g. to pass them to
+//! `Host::value` or `eval::call`. Structs and maps become sets, sequences
+//! and tuples lists, `None` and `()` null. Enum variants without data are
+//! strings, the others a set with the variant's name as the only attribute.
+use crate::de::Error;
+use crate::expr::GcExpr;
+use crate::host::Value;
+use gc_arena::MutationContext;
+use serde::ser::{self, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    value.serialize(Serializer)
+}
+
+pub fn to_expr<'gc, T: Serialize + ?Sized>(mc: MutationContext<'gc, '_>, value: &T) -> Result<GcExpr<'gc>, Error> {
+    Ok(to_value(value)?.to_expr(mc))
+}
+
+pub struct Serializer;
+
+/// `{ ${variant} = value; }`
+fn tagged(variant: &str, value: Value) -> Value {
+    let mut attrs = BTreeMap::new();
+    attrs.insert(variant.to_string(), value);
+    Value::AttrSet(attrs)
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeSet;
+    type SerializeStruct = SerializeSet;
+    type SerializeStructVariant = SerializeSet;
+
+    fn serialize_bool(self, b: bool) -> Result<Value, Error> {
+        Ok(Value::Bool(b))
+    }
+
+    fn serialize_i8(self, i: i8) -> Result<Value, Error> {
+        self.serialize_i64(i.into())
+    }
+
+    fn serialize_i16(self, i: i16) -> Result<Value, Error> {
+        self.serialize_i64(i.into())
+    }
+
+    fn serialize_i32(self, i: i32) -> Result<Value, Error> {
+        self.serialize_i64(i.into())
+    }
+
+    fn serialize_i64(self, i: i64) -> Result<Value, Error> {
+        Ok(Value::Int(i))
+    }
+
+    fn serialize_u8(self, i: u8) -> Result<Value, Error> {
+        self.serialize_i64(i.into())
+    }
+
+    fn serialize_u16(self, i: u16) -> Result<Value, Error> {
+        self.serialize_i64(i.into())
+    }
+
+    fn serialize_u32(self, i: u32) -> Result<Value, Error> {
+        self.serialize_i64(i.into())
+    }
+
+    /// nix integers are 64 bit signed, anything bigger is an error.
+    fn serialize_u64(self, i: u64) -> Result<Value, Error> {
+        match i64::try_from(i) {
+            Ok(i) => self.serialize_i64(i),
+            Err(_) => Err(Error::Message(format!("{} is too big for a nix integer", i))),
+        }
+    }
+
+    fn serialize_f32(self, x: f32) -> Result<Value, Error> {
+        self.serialize_f64(x.into())
+    }
+
+    fn serialize_f64(self, x: f64) -> Result<Value, Error> {
+        Ok(Value::Float(x))
+    }
+
+    fn serialize_char(self, c: char) -> Result<Value, Error> {
+        Ok(Value::String(c.to_string()))
+    }
+
+    fn serialize_str(self, s: &str) -> Result<Value, Error> {
+        Ok(Value::String(s.to_string()))
+    }
+
+    fn serialize_bytes(self, bytes: &[u8]) -> Result<Value, Error> {
+        Ok(Value::List(bytes.iter().map(|b| Value::Int((*b).into())).collect()))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Value, Error> {
+        Ok(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(tagged(variant, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, Error> {
+        Ok(SerializeList {
+            variant: None,
+            elems: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList {
+            variant: Some(variant),
+            elems: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<SerializeSet, Error> {
+        Ok(SerializeSet {
+            variant: None,
+            attrs: BTreeMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<SerializeSet, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<SerializeSet, Error> {
+        Ok(SerializeSet {
+            variant: Some(variant),
+            attrs: BTreeMap::new(),
+            key: None,
+        })
+    }
+}
+
+pub struct SerializeList {
+    variant: Option<&'static str>,
+    elems: Vec<Value>,
+}
+
+impl SerializeList {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.elems.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let list = Value::List(self.elems);
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, list),
+            None => list,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+pub struct SerializeSet {
+    variant: Option<&'static str>,
+    attrs: BTreeMap<String, Value>,
+    // the name `serialize_key` got, waiting for its value
+    key: Option<String>,
+}
+
+impl SerializeSet {
+    fn insert<T: Serialize + ?Sized>(&mut self, name: String, value: &T) -> Result<(), Error> {
+        self.attrs.insert(name, to_value(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let set = Value::AttrSet(self.attrs);
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, set),
+            None => set,
+        })
+    }
+}
+
+impl ser::SerializeMap for SerializeSet {
+    type Ok = Value;
+    type Error = Error;
+
+    /// Attribute names are strings, so keys have to be too.
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match to_value(key)? {
+            Value::String(name) => {
+                self.key = Some(name);
+                Ok(())
+            }
+            key => Err(Error::Message(format!("attribute names must be strings, not {:?}", key))),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let name = self.key.take().expect("serialize_value before serialize_key");
+        self.insert(name, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeSet {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, name: &'static str, value: &T) -> Result<(), Error> {
+        self.insert(name.to_string(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeSet {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, name: &'static str, value: &T) -> Result<(), Error> {
+        self.insert(name.to_string(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::de::from_expr;
+    use crate::eval::{call, eval};
+    use crate::expr_parser::exprParser;
+    use crate::lexer::nix_lexer::Lexer;
+    use gc_arena::rootless_arena;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Service {
+        name: String,
+        ports: Vec<u16>,
+        limits: Option<(u32, f64)>,
+        mode: Mode,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Mode {
+        Off,
+        On(u8),
+    }
+
+    #[test]
+    fn check_serialize() {
+        let service = Service {
+            name: "web".to_string(),
+            ports: vec![80, 443],
+            limits: None,
+            mode: Mode::On(3),
+        };
+        let value = to_value(&service).unwrap();
+        let mut expected = BTreeMap::new();
+        expected.insert("name".to_string(), Value::from("web"));
+        expected.insert("ports".to_string(), Value::from(vec![80, 443]));
+        expected.insert("limits".to_string(), Value::Null);
+        let mut mode = BTreeMap::new();
+        mode.insert("On".to_string(), 3);
+        expected.insert("mode".to_string(), Value::from(mode));
+        assert_eq!(value, Value::AttrSet(expected));
+        assert_eq!(to_value(&Mode::Off), Ok(Value::from("Off")));
+        assert!(to_value(&u64::MAX).is_err());
+        let mut not_a_set = BTreeMap::new();
+        not_a_set.insert(1, 2);
+        assert!(to_value(&not_a_set).is_err());
+
+        // round trip through a nix function
+        rootless_arena(|mc| {
+            let src = "s: s // { ports = s.ports ++ [ 8080 ]; limits = [ 10 0.5 ]; mode = \"Off\"; }";
+            let f = eval(mc, exprParser::new().parse(mc, Lexer::new(src, Vec::new(), 0)).unwrap(), 100).unwrap();
+            let result = call(mc, f, &[value], 1000).unwrap();
+            assert_eq!(
+                from_expr::<Service>(mc, result, 1000),
+                Ok(Service {
+                    ports: vec![80, 443, 8080],
+                    limits: Some((10, 0.5)),
+                    mode: Mode::Off,
+                    ..service
+                })
+            );
+            assert!(matches!(*to_expr(mc, &[1, 2]).unwrap(), crate::expr::Expr::List(ref l) if l.len() == 2));
+        });
+    }
+}